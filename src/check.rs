@@ -0,0 +1,142 @@
+//! Verifying the integrity of backups.
+//!
+//! Every archive carries its own manifest as its last entry, holding the size and
+//! SHA-256 checksum of each file. Checking an archive decompresses it fully and
+//! compares every entry against that manifest.
+
+use std::collections::HashMap;
+use std::io::{self, Read};
+
+use flate2::read::GzDecoder;
+use log::warn;
+use serde::Serialize;
+
+use crate::manifest::{self, Manifest};
+use crate::restore::archive_path_components;
+use crate::snapshot::OutputFormat;
+use crate::utils;
+
+/// Which backups to check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckSelection {
+    /// A single backup by its name, its date (`YYYY-MM-DD`) or `latest`.
+    Snapshot(String),
+    /// Every backup.
+    All,
+    /// The given number of randomly chosen backups.
+    Sample(usize),
+}
+
+/// Result of checking a single archive.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct CheckReport {
+    pub archive: String,
+    /// Number of files found in the archive.
+    pub entries: usize,
+    /// Number of files whose checksum matched the embedded manifest.
+    pub verified: usize,
+    pub problems: Vec<String>,
+}
+
+impl CheckReport {
+    pub fn new(archive: &str) -> Self {
+        CheckReport { archive: String::from(archive), ..Default::default() }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Decompresses a gzipped tar stream and checks every entry against the manifest
+/// embedded in it.
+///
+/// Problems are collected in the returned report instead of stopping at the first
+/// one. `reader` is always read to its end, so it may be a download in progress.
+pub fn check_archive<R: Read>(mut reader: R, archive: &str) -> CheckReport {
+    let mut report = CheckReport::new(archive);
+    let mut checksums: HashMap<String, (u64, String)> = HashMap::new();
+    let mut embedded: Option<Manifest> = None;
+
+    let read = (|| -> io::Result<()> {
+        let mut tar = tar::Archive::new(GzDecoder::new(&mut reader));
+
+        for entry in tar.entries()? {
+            let mut entry = entry?;
+            let path = archive_path_components(&String::from_utf8_lossy(&entry.path_bytes())).join("/");
+
+            if path == manifest::EMBEDDED_MANIFEST_PATH {
+                let mut contents = Vec::new();
+                entry.read_to_end(&mut contents)?;
+                embedded = Some(serde_json::from_slice(&contents)?);
+                continue;
+            }
+
+            let mut hashing_reader = utils::HashingReader::new(&mut entry);
+            let size = io::copy(&mut hashing_reader, &mut io::sink())?;
+            checksums.insert(path, (size, hashing_reader.hex_digest()));
+            report.entries += 1;
+        }
+
+        // Reading the gzip trailer verifies the checksum of the whole stream.
+        io::copy(&mut tar.into_inner(), &mut io::sink())?;
+        Ok(())
+    })();
+
+    if let Err(e) = read {
+        report.problems.push(format!("Archive is corrupt or truncated: {}", e));
+        // Whatever is left is still read, so that a download feeding `reader` can finish.
+        let _ = io::copy(&mut reader, &mut io::sink());
+        return report;
+    }
+
+    let Some(embedded) = embedded else {
+        warn!("{:?} has no embedded manifest, only its decompression was checked.", archive);
+        return report;
+    };
+
+    for entry in embedded.entries.iter() {
+        match checksums.remove(&entry.path) {
+            None => report.problems.push(format!("Missing file: {}", entry.path)),
+            Some((size, _)) if size != entry.size => {
+                report.problems.push(format!("Size mismatch of {}: expected {} bytes, found {}", entry.path, entry.size, size))
+            }
+            Some((_, sha256)) => match &entry.sha256 {
+                Some(expected) if *expected != sha256 => {
+                    report.problems.push(format!("Checksum mismatch of {}", entry.path))
+                }
+                Some(_) => report.verified += 1,
+                None => (),
+            },
+        }
+    }
+
+    for path in checksums.keys() {
+        report.problems.push(format!("Unexpected file not in the manifest: {}", path));
+    }
+
+    report
+}
+
+/// Prints the check reports to stdout in the given format.
+pub fn print_reports(reports: &[CheckReport], format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(reports)?),
+        OutputFormat::Table => {
+            for report in reports {
+                println!(
+                    "{}  {}  ({} files, {} verified)",
+                    if report.is_ok() { "OK    " } else { "FAILED" },
+                    report.archive,
+                    report.entries,
+                    report.verified
+                );
+                for problem in report.problems.iter() {
+                    println!("        {}", problem);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,793 @@
+//! Reading and validating the settings file.
+//!
+//! Settings can be written in TOML, YAML or JSON, told apart by the file's extension.
+//! Errors while parsing point at the line and column of the mistake, and the parsed
+//! settings are validated as a whole, reporting every problem at once.
+//!
+//! Any setting can be overridden without editing the file, which takes precedence as
+//! follows: `--set` flags, then `BACKUPRS_*` environment variables, then the settings
+//! file, then the defaults. See `Override`.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use crate::credentials::{CredentialSource, MfaSource, Secret};
+use crate::error::{ConfigParseError, InvalidConfigError, SettingsNotFoundError, UnknownJobError};
+use crate::naming::{self, CollisionPolicy, NamingTemplate};
+use crate::paths::{self, Env};
+use crate::restore::backup_folder_name;
+use crate::retention::RetentionPolicy;
+
+/// Names of the settings files looked for, in order.
+pub const SETTINGS_FILES: [&str; 4] = ["settings.toml", "settings.yaml", "settings.yml", "settings.json"];
+/// Environment variable pointing at the settings file.
+pub const CONFIG_ENV_VAR: &str = "BACKUPRS_CONFIG";
+
+/// Prefix of the environment variables overriding settings.
+pub const ENV_PREFIX: &str = "BACKUPRS_";
+/// Name of the settings' source if there is no settings file.
+const ENV_SOURCE: &str = "environment";
+/// What secrets are replaced with when the settings are shown.
+const REDACTED: &str = "<redacted>";
+
+/// Settings file given on the command line, see `find_settings_file`.
+static SETTINGS_FILE_FLAG: OnceLock<PathBuf> = OnceLock::new();
+/// Settings overridden on the command line, see `overrides`.
+static SETTING_FLAGS: OnceLock<Vec<Override>> = OnceLock::new();
+
+/// Format of a settings file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Tells the format from the file's extension, JSON if it isn't known.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|x| x.to_str()).map(|x| x.to_ascii_lowercase()).as_deref() {
+            Some("toml") => ConfigFormat::Toml,
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Json,
+        }
+    }
+}
+
+/// Folder in MEGA the backups are uploaded to, unless set otherwise.
+pub const DEFAULT_BACKUP_FOLDER: &str = "/Root/Backups";
+/// Files bigger than this many MB are left out of the backups, unless set otherwise.
+pub const DEFAULT_MAX_FILE_MB: u64 = 512;
+
+fn default_backup_folder() -> String {
+    String::from(DEFAULT_BACKUP_FOLDER)
+}
+
+fn default_max_file_mb() -> u64 {
+    DEFAULT_MAX_FILE_MB
+}
+
+/// Settings of a named job. Whatever isn't set is taken from the top level of the settings.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct JobSettings {
+    #[serde(default)]
+    pub dirs_to_backup: Vec<String>,
+    pub dirs_to_ignore: Option<Vec<String>>,
+    pub max_file_mb: Option<u64>,
+    pub backup_folder: Option<String>,
+    pub retention: Option<RetentionPolicy>,
+}
+
+/// A job with its settings filled in from the top level, ready to run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Job {
+    /// Name of the job, also the `{profile}` of its backups.
+    pub name: String,
+    pub dirs_to_backup: Vec<String>,
+    pub dirs_to_ignore: Vec<String>,
+    pub max_file_mb: u64,
+    pub backup_folder: String,
+    pub retention: RetentionPolicy,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SettingsEnv {
+    /// Base64 encoded email, only read if `credentials` isn't set.
+    #[serde(default)]
+    pub email: String,
+    /// Base64 encoded password, only read if `credentials` isn't set.
+    #[serde(default)]
+    pub password: String,
+    /// Where the email and the password are read from.
+    #[serde(default)]
+    pub credentials: Option<CredentialSource>,
+    /// How to get the two-factor authentication code, if the account has 2FA enabled.
+    #[serde(default)]
+    pub mfa: Option<MfaSource>,
+    /// Folders backed up if there are no `jobs`.
+    #[serde(default)]
+    pub dirs_to_backup: Vec<String>,
+    /// Names of folders left out of the backups wherever they are, e.g. `.git`.
+    #[serde(default)]
+    pub dirs_to_ignore: Vec<String>,
+    /// Files bigger than this are left out of the backups.
+    #[serde(default = "default_max_file_mb")]
+    pub max_file_mb: u64,
+    /// Folder in MEGA the backups are uploaded to.
+    #[serde(default = "default_backup_folder")]
+    pub backup_folder: String,
+    /// Named jobs, each backing up its own folders. The other settings are the
+    /// defaults of every job.
+    #[serde(default)]
+    pub jobs: BTreeMap<String, JobSettings>,
+    /// Which backups to keep when pruning. Keeps the 10 newest backups if it's not set.
+    #[serde(default = "RetentionPolicy::legacy")]
+    pub retention: RetentionPolicy,
+    /// Template of the backups' names, see `NamingTemplate`.
+    #[serde(default)]
+    pub naming: NamingTemplate,
+    /// What to do if a backup with the same name already exists.
+    #[serde(default)]
+    pub on_collision: CollisionPolicy
+}
+
+/// Reads the settings from `file` instead of looking for them, e.g. because of a
+/// `--config` flag. Only the first call has an effect.
+pub fn use_settings_file(file: PathBuf) {
+    let _ = SETTINGS_FILE_FLAG.set(file);
+}
+
+/// Returns the settings file to read, in order:
+///
+/// 1. the file given to `use_settings_file`,
+/// 2. the file `BACKUPRS_CONFIG` points at,
+/// 3. the first of `SETTINGS_FILES` in the working directory,
+/// 4. the first of `SETTINGS_FILES` in the config folder, e.g. `~/.config/backuprs`.
+///
+/// # Returns
+///
+/// Returns `None` if there is no settings file, but settings are given by overrides,
+/// e.g. in a container configured only through the environment.
+///
+/// # Errors
+///
+/// * `SettingsNotFoundError` listing where the settings were looked for, if the file
+///   given explicitly doesn't exist, or none of the others does and nothing is overridden.
+pub fn find_settings_file() -> Result<Option<PathBuf>, SettingsNotFoundError> {
+    let explicit = SETTINGS_FILE_FLAG.get().cloned()
+        .or_else(|| std::env::var_os(CONFIG_ENV_VAR).filter(|x| !x.is_empty()).map(PathBuf::from));
+    let dirs: Vec<PathBuf> = std::iter::once(PathBuf::from(".")).chain(paths::config_dir()).collect();
+
+    find_settings_file_in(explicit, &dirs, overrides().iter().any(|x| x.is_setting()))
+}
+
+/// Looks for the settings file like `find_settings_file`.
+///
+/// # Arguments
+///
+/// * `explicit` - The file given by the flag or `BACKUPRS_CONFIG`, which must exist.
+/// * `dirs` - Folders to look for `SETTINGS_FILES` in, in order.
+/// * `overridden` - Whether any setting is overridden, so no file is needed.
+pub fn find_settings_file_in(explicit: Option<PathBuf>, dirs: &[PathBuf], overridden: bool) -> Result<Option<PathBuf>, SettingsNotFoundError> {
+    if let Some(file) = explicit {
+        if !file.is_file() {
+            return Err(SettingsNotFoundError { searched: vec![file] });
+        }
+        return Ok(Some(file));
+    }
+
+    let candidates: Vec<PathBuf> = dirs.iter()
+        .flat_map(|dir| SETTINGS_FILES.iter().map(move |name| dir.join(name)))
+        .collect();
+
+    match candidates.iter().find(|file| file.is_file()) {
+        Some(file) => Ok(Some(file.clone())),
+        None if overridden => Ok(None),
+        None => Err(SettingsNotFoundError { searched: candidates }),
+    }
+}
+
+/// Returns the name of where the settings come from, used in errors: the settings
+/// file, or `environment` if there is none.
+pub fn settings_source(file_path: Option<&Path>) -> String {
+    file_path.map_or_else(|| String::from(ENV_SOURCE), |x| x.display().to_string())
+}
+
+/// A setting overridden outside of the settings file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Override {
+    /// Where the override comes from, e.g. `BACKUPRS_RETENTION__KEEP_DAILY`.
+    pub source: String,
+    /// Path of the setting, e.g. `retention.keep_daily`.
+    pub path: String,
+    pub value: String,
+}
+
+impl Override {
+    /// Parses a `--set` flag.
+    /// E.g.: "retention.keep_daily=7" ----> `retention.keep_daily` set to `7`
+    ///
+    /// # Returns
+    ///
+    /// Returns `None` if the flag has no `=`.
+    pub fn from_flag(flag: &str) -> Option<Self> {
+        let (path, value) = flag.split_once('=')?;
+        Some(Override {
+            source: format!("--set {}", path),
+            path: String::from(path.trim()),
+            value: String::from(value),
+        })
+    }
+
+    /// Parses an environment variable, `__` separating the nested settings.
+    /// E.g.: "BACKUPRS_RETENTION__KEEP_DAILY" ----> `retention.keep_daily`
+    ///
+    /// The path is lowercased, and matched against the settings ignoring case, so that
+    /// e.g. `BACKUPRS_JOBS__PHOTOS__MAX_FILE_MB` overrides the job `Photos`.
+    ///
+    /// # Returns
+    ///
+    /// Returns `None` if the variable isn't an override, i.e. it doesn't start with
+    /// `BACKUPRS_` or it is `BACKUPRS_CONFIG`.
+    pub fn from_env(name: &str, value: &str) -> Option<Self> {
+        if name == CONFIG_ENV_VAR {
+            return None;
+        }
+        Some(Override {
+            source: String::from(name),
+            path: name.strip_prefix(ENV_PREFIX)?.to_ascii_lowercase().replace("__", "."),
+            value: String::from(value),
+        })
+    }
+
+    /// Whether the override is an environment variable rather than a `--set` flag.
+    fn is_from_env(&self) -> bool {
+        self.source.starts_with(ENV_PREFIX)
+    }
+
+    /// Whether the override is to be applied. Environment variables not naming any
+    /// setting, e.g. a `BACKUPRS_TOKEN` meant for another tool, are ignored, while
+    /// `--set` flags always are applied, so that mistakes in them are reported.
+    pub fn is_setting(&self) -> bool {
+        if !self.is_from_env() {
+            return true;
+        }
+        let defaults = parse_settings("{}", ConfigFormat::Json, ENV_SOURCE).expect("The defaults are valid settings.");
+        let top_level = self.path.split('.').next().unwrap_or_default();
+        serde_json::to_value(defaults).is_ok_and(|x| x.as_object().is_some_and(|x| x.contains_key(top_level)))
+    }
+}
+
+/// Overrides settings with `--set` flags, e.g. `--set retention.keep_daily=7`.
+/// Only the first call has an effect.
+pub fn use_setting_flags(flags: Vec<Override>) {
+    let _ = SETTING_FLAGS.set(flags);
+}
+
+/// Returns every override, in the order they are applied: the environment variables
+/// sorted by name, then the `--set` flags, so that the flags take precedence.
+pub fn overrides() -> Vec<Override> {
+    let mut overrides: Vec<_> = std::env::vars()
+        .filter_map(|(name, value)| Override::from_env(&name, &value))
+        .collect();
+    overrides.sort_by(|a, b| a.source.cmp(&b.source));
+    overrides.extend(SETTING_FLAGS.get().into_iter().flatten().cloned());
+    overrides
+}
+
+/// Overrides settings, in order. A value is read as JSON if it is valid JSON for the
+/// setting (e.g. `7`, `true` or `["~/a", "~/b"]`), and as text otherwise.
+///
+/// Keys are matched ignoring case if there is no exact match, e.g. `jobs.photos`
+/// overrides the job `Photos`. Environment variables not naming any setting, e.g. a
+/// `BACKUPRS_TOKEN` meant for another tool, are ignored with a warning.
+///
+/// # Errors
+///
+/// * `ConfigParseError` naming the override if the setting doesn't exist, or the value
+///   doesn't fit it.
+pub fn apply_overrides(settings: SettingsEnv, overrides: &[Override]) -> Result<SettingsEnv, ConfigParseError> {
+    let mut settings = settings;
+    let mut document = serde_json::to_value(&settings).map_err(|e| ConfigParseError {
+        file: String::from(ENV_SOURCE), line: None, column: None, message: e.to_string()
+    })?;
+
+    for item in overrides {
+        let error = |message: String| ConfigParseError { file: item.source.clone(), line: None, column: None, message };
+        if item.path.split('.').any(|key| key.is_empty()) {
+            return Err(error(format!("{:?} is not a valid setting.", item.path)));
+        }
+        if !item.is_setting() {
+            log::warn!("Ignoring {}, as it is not a setting.", item.source);
+            continue;
+        }
+
+        let text = serde_json::Value::String(item.value.clone());
+        let candidates = serde_json::from_str(&item.value).ok()
+            .filter(|x: &serde_json::Value| !x.is_string())
+            .into_iter()
+            .chain(std::iter::once(text));
+
+        let mut first_error = None;
+        for value in candidates {
+            let mut overridden = document.clone();
+            let mut target = &mut overridden;
+            for key in item.path.split('.') {
+                if !target.is_object() {
+                    *target = serde_json::Value::Object(Default::default());
+                }
+                let object = target.as_object_mut().unwrap();
+                let key = if object.contains_key(key) {
+                    String::from(key)
+                } else {
+                    object.keys().find(|x| x.eq_ignore_ascii_case(key)).cloned().unwrap_or_else(|| String::from(key))
+                };
+                target = object.entry(key).or_insert(serde_json::Value::Null);
+            }
+            *target = value;
+
+            match serde_json::from_value(overridden.clone()) {
+                Ok(parsed) => {
+                    settings = parsed;
+                    document = overridden;
+                    first_error = None;
+                    break;
+                }
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(e) = first_error {
+            return Err(error(e.to_string()));
+        }
+    }
+
+    Ok(settings)
+}
+
+/// Returns the 1-based line and column of a byte offset.
+fn line_column(contents: &str, offset: usize) -> (usize, usize) {
+    let before = &contents[..offset.min(contents.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|&c| c != '\n').count() + 1;
+    (line, column)
+}
+
+/// Removes the location serde_json and serde_yaml append to their messages, since
+/// it is reported separately.
+fn strip_location(message: String) -> String {
+    match message.rfind(" at line ") {
+        Some(i) => String::from(&message[..i]),
+        None => message,
+    }
+}
+
+/// Parses settings.
+///
+/// # Arguments
+///
+/// * `contents` - Contents of the settings file.
+/// * `format` - Format the settings are written in.
+/// * `file` - Name of the file, only used in errors.
+///
+/// # Errors
+///
+/// * `ConfigParseError` with the line and column of the mistake, e.g. an unknown or
+///   missing field, or a value of the wrong type.
+pub fn parse_settings(contents: &str, format: ConfigFormat, file: &str) -> Result<SettingsEnv, ConfigParseError> {
+    let error = |location: Option<(usize, usize)>, message: String| ConfigParseError {
+        file: String::from(file),
+        line: location.map(|x| x.0),
+        column: location.map(|x| x.1),
+        message,
+    };
+
+    match format {
+        ConfigFormat::Json => serde_json::from_str(contents).map_err(|e| {
+            error(Some((e.line(), e.column())).filter(|x| x.0 > 0), strip_location(e.to_string()))
+        }),
+        ConfigFormat::Toml => toml::from_str(contents).map_err(|e| {
+            let location = e.span().map(|x| line_column(contents, x.start));
+            error(location, String::from(e.message().trim()))
+        }),
+        ConfigFormat::Yaml => serde_yaml::from_str(contents).map_err(|e| {
+            let location = e.location().map(|x| (x.line(), x.column()));
+            error(location, strip_location(e.to_string()))
+        }),
+    }
+}
+
+/// Checks the folders to back up of a job, `prefix` being where they are in the settings.
+fn folder_problems(prefix: &str, dirs_to_backup: &[String], check_sources: bool, problems: &mut Vec<String>) {
+    if dirs_to_backup.is_empty() {
+        problems.push(format!("`{}dirs_to_backup` is empty, there is nothing to back up.", prefix));
+    }
+
+    for (i, dir) in dirs_to_backup.iter().enumerate() {
+        let name = backup_folder_name(dir);
+        let same_name = dirs_to_backup[..i].iter().position(|x| backup_folder_name(x) == name);
+
+        if name.is_none() {
+            problems.push(format!("`{}dirs_to_backup[{}]`: {:?} is not a folder path.", prefix, i, dir));
+        } else if let Some(j) = same_name {
+            problems.push(format!(
+                "`{0}dirs_to_backup[{1}]`: {2:?} has the same folder name as `{0}dirs_to_backup[{3}]`, they would be stored in the same place in the backup.", prefix, i, dir, j
+            ));
+        } else if check_sources && !Path::new(dir).is_dir() {
+            problems.push(format!("`{}dirs_to_backup[{}]`: {:?} doesn't exist or is not a folder.", prefix, i, dir));
+        }
+    }
+}
+
+/// Checks the names of the folders to leave out, `prefix` being where they are in the settings.
+fn ignore_problems(prefix: &str, dirs_to_ignore: &[String], problems: &mut Vec<String>) {
+    for (i, name) in dirs_to_ignore.iter().enumerate() {
+        if name.trim().is_empty() {
+            problems.push(format!("`{}dirs_to_ignore[{}]` is empty.", prefix, i));
+        } else if name.contains(['/', '\\']) || name.contains(['*', '?', '[']) {
+            problems.push(format!(
+                "`{}dirs_to_ignore[{}]`: {:?} must be a plain folder name like `.git`, paths and patterns aren't supported.", prefix, i, name
+            ));
+        }
+    }
+}
+
+/// Checks a folder in MEGA, `prefix` being where it is in the settings.
+fn backup_folder_problems(prefix: &str, backup_folder: &str, problems: &mut Vec<String>) {
+    if backup_folder != "/Root" && !backup_folder.starts_with("/Root/") {
+        problems.push(format!(
+            "`{}backup_folder`: {:?} must be a folder of the cloud drive, starting with `/Root/`.", prefix, backup_folder
+        ));
+    }
+}
+
+/// Expands a value of the settings in place, see `paths::expand_in`, `location` being
+/// where it is in the settings.
+fn expand_string(env: Env, location: &str, value: &mut String, problems: &mut Vec<String>) {
+    match paths::expand_in(value, env) {
+        Ok(expanded) => *value = expanded,
+        Err(reason) => problems.push(format!("`{}`: {:?} can't be expanded, {}.", location, value, reason)),
+    }
+}
+
+fn expand_path(env: Env, location: &str, path: &mut PathBuf, problems: &mut Vec<String>) {
+    let mut value = path.to_string_lossy().into_owned();
+    expand_string(env, location, &mut value, problems);
+    *path = PathBuf::from(value);
+}
+
+fn expand_secret(env: Env, location: &str, secret: &mut Secret, problems: &mut Vec<String>) {
+    if let Secret::File(path) = secret {
+        expand_path(env, &format!("{}.file", location), path, problems);
+    }
+}
+
+impl SettingsEnv {
+    /// Checks the settings, returning every problem found.
+    ///
+    /// # Arguments
+    ///
+    /// * `check_sources` - Also check that the folders to back up exist, which only
+    ///   matters on the machine making the backups.
+    pub fn problems(&self, check_sources: bool) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        let legacy_credentials = !self.email.is_empty() || !self.password.is_empty();
+        if self.credentials.is_some() && legacy_credentials {
+            problems.push(String::from("`email` and `password` can't be set together with `credentials`."));
+        } else if self.credentials.is_none() && !legacy_credentials {
+            problems.push(String::from(
+                "`credentials` is required, e.g. `credentials = { email = { value = \"me@example.com\" }, password = { command = \"pass show mega\" } }`."
+            ));
+        } else if self.credentials.is_none() && (self.email.is_empty() || self.password.is_empty()) {
+            problems.push(String::from("`email` and `password` must be set together."));
+        }
+
+        if self.jobs.is_empty() {
+            folder_problems("", &self.dirs_to_backup, check_sources, &mut problems);
+        } else if !self.dirs_to_backup.is_empty() {
+            problems.push(String::from("`dirs_to_backup` can't be set together with `jobs`, move the folders into the jobs."));
+        }
+        ignore_problems("", &self.dirs_to_ignore, &mut problems);
+        backup_folder_problems("", &self.backup_folder, &mut problems);
+
+        if let Err(e) = self.retention.validate() {
+            problems.push(format!("`retention`: {}", e.reason));
+        }
+
+        for (name, job) in self.jobs.iter() {
+            let prefix = format!("jobs.{}.", name);
+            if name.trim().is_empty() {
+                problems.push(String::from("`jobs`: job names can't be empty."));
+            }
+            folder_problems(&prefix, &job.dirs_to_backup, check_sources, &mut problems);
+            if let Some(dirs_to_ignore) = &job.dirs_to_ignore {
+                ignore_problems(&prefix, dirs_to_ignore, &mut problems);
+            }
+            if let Some(backup_folder) = &job.backup_folder {
+                backup_folder_problems(&prefix, backup_folder, &mut problems);
+            }
+            if let Some(Err(e)) = job.retention.as_ref().map(|x| x.validate()) {
+                problems.push(format!("`{}retention`: {}", prefix, e.reason));
+            }
+        }
+
+        // Jobs sharing a folder are only told apart by the `{profile}` in the names
+        // of their backups, otherwise pruning one would delete the others' backups.
+        let jobs = self.jobs();
+        for (i, job) in jobs.iter().enumerate() {
+            let same_folder = jobs[..i].iter().find(|x| {
+                x.backup_folder == job.backup_folder
+                && (!self.naming.has_profile() || naming::sanitize(&x.name) == naming::sanitize(&job.name))
+            });
+            if let Some(other) = same_folder {
+                problems.push(format!(
+                    "`jobs.{}` and `jobs.{}` would store their backups under the same names in {:?}, give them different `backup_folder`s{}.",
+                    other.name, job.name, job.backup_folder,
+                    if self.naming.has_profile() { " or names" } else { " or add `{profile}` to `naming`" }
+                ));
+            }
+        }
+
+        problems
+    }
+
+    /// Expands `~`, `$VAR`, `${VAR}` and `{hostname}` in the paths of the settings:
+    /// the folders to back up and to ignore, the backup folders and the files the
+    /// credentials are read from. See `paths::expand_in`.
+    ///
+    /// # Returns
+    ///
+    /// Returns the paths that couldn't be expanded, e.g. because a variable isn't set.
+    pub fn expand_paths(&mut self) -> Vec<String> {
+        self.expand_paths_in(&paths::process_env)
+    }
+
+    /// Expands the paths like `expand_paths`, reading the variables from the environment `env`.
+    pub fn expand_paths_in(&mut self, env: Env) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        for (i, dir) in self.dirs_to_backup.iter_mut().enumerate() {
+            expand_string(env, &format!("dirs_to_backup[{}]", i), dir, &mut problems);
+        }
+        for (i, name) in self.dirs_to_ignore.iter_mut().enumerate() {
+            expand_string(env, &format!("dirs_to_ignore[{}]", i), name, &mut problems);
+        }
+        expand_string(env, "backup_folder", &mut self.backup_folder, &mut problems);
+
+        for (name, job) in self.jobs.iter_mut() {
+            for (i, dir) in job.dirs_to_backup.iter_mut().enumerate() {
+                expand_string(env, &format!("jobs.{}.dirs_to_backup[{}]", name, i), dir, &mut problems);
+            }
+            for (i, ignored) in job.dirs_to_ignore.iter_mut().flatten().enumerate() {
+                expand_string(env, &format!("jobs.{}.dirs_to_ignore[{}]", name, i), ignored, &mut problems);
+            }
+            if let Some(backup_folder) = &mut job.backup_folder {
+                expand_string(env, &format!("jobs.{}.backup_folder", name), backup_folder, &mut problems);
+            }
+        }
+
+        match &mut self.credentials {
+            Some(CredentialSource::Encrypted { encrypted_file, passphrase }) => {
+                expand_path(env, "credentials.encrypted_file", encrypted_file, &mut problems);
+                expand_secret(env, "credentials.passphrase", passphrase, &mut problems);
+            }
+            Some(CredentialSource::Separate { email, password }) => {
+                expand_secret(env, "credentials.email", email, &mut problems);
+                expand_secret(env, "credentials.password", password, &mut problems);
+            }
+            None => (),
+        }
+        if let Some(MfaSource::Totp(secret)) = &mut self.mfa {
+            expand_secret(env, "mfa.totp", secret, &mut problems);
+        }
+
+        problems
+    }
+
+    /// Returns a copy of the settings with the secrets written in them replaced by
+    /// `<redacted>`, to be shown. Secrets read from elsewhere only show where from.
+    pub fn redacted(&self) -> Self {
+        let redact = |secret: &Secret| match secret {
+            Secret::Value(_) => Secret::Value(String::from(REDACTED)),
+            other => other.clone(),
+        };
+        let redact_text = |value: &str| if value.is_empty() { String::new() } else { String::from(REDACTED) };
+
+        let mut settings = self.clone();
+        settings.password = redact_text(&self.password);
+        settings.credentials = match &self.credentials {
+            Some(CredentialSource::Encrypted { encrypted_file, passphrase }) => Some(CredentialSource::Encrypted {
+                encrypted_file: encrypted_file.clone(),
+                passphrase: redact(passphrase),
+            }),
+            Some(CredentialSource::Separate { email, password }) => Some(CredentialSource::Separate {
+                email: email.clone(),
+                password: redact(password),
+            }),
+            None => None,
+        };
+        if let Some(MfaSource::Totp(secret)) = &self.mfa {
+            settings.mfa = Some(MfaSource::Totp(redact(secret)));
+        }
+        settings
+    }
+
+    /// Returns every job, sorted by name. Without `jobs`, the top level of the
+    /// settings is a single job named `default`.
+    pub fn jobs(&self) -> Vec<Job> {
+        if self.jobs.is_empty() {
+            return vec![Job {
+                name: String::from(naming::DEFAULT_PROFILE),
+                dirs_to_backup: self.dirs_to_backup.clone(),
+                dirs_to_ignore: self.dirs_to_ignore.clone(),
+                max_file_mb: self.max_file_mb,
+                backup_folder: self.backup_folder.clone(),
+                retention: self.retention.clone(),
+            }];
+        }
+
+        self.jobs.iter()
+            .map(|(name, job)| Job {
+                name: name.clone(),
+                dirs_to_backup: job.dirs_to_backup.clone(),
+                dirs_to_ignore: job.dirs_to_ignore.clone().unwrap_or_else(|| self.dirs_to_ignore.clone()),
+                max_file_mb: job.max_file_mb.unwrap_or(self.max_file_mb),
+                backup_folder: job.backup_folder.clone().unwrap_or_else(|| self.backup_folder.clone()),
+                retention: job.retention.clone().unwrap_or_else(|| self.retention.clone()),
+            })
+            .collect()
+    }
+
+    /// Returns the jobs with the given names, in the given order, or every job if
+    /// `names` is empty.
+    ///
+    /// # Errors
+    ///
+    /// * `UnknownJobError` if there is no job with one of the names.
+    pub fn select_jobs(&self, names: &[String]) -> Result<Vec<Job>, UnknownJobError> {
+        let jobs = self.jobs();
+        if names.is_empty() {
+            return Ok(jobs);
+        }
+
+        names.iter()
+            .map(|name| jobs.iter().find(|job| &job.name == name).cloned().ok_or_else(|| UnknownJobError {
+                job: Some(name.clone()),
+                jobs: jobs.iter().map(|x| x.name.clone()).collect(),
+            }))
+            .collect()
+    }
+
+    /// Returns a single job, which may only be left out if there is just one.
+    ///
+    /// # Errors
+    ///
+    /// * `UnknownJobError` if there is no job named `name`, or if `name` is `None`
+    ///   but there are several jobs.
+    pub fn job(&self, name: Option<&str>) -> Result<Job, UnknownJobError> {
+        let mut jobs = match name {
+            Some(name) => self.select_jobs(&[String::from(name)])?,
+            None => self.jobs(),
+        };
+        if jobs.len() > 1 {
+            return Err(UnknownJobError { job: None, jobs: jobs.into_iter().map(|x| x.name).collect() });
+        }
+        Ok(jobs.remove(0))
+    }
+}
+
+/// Reads and validates a settings file without reading the credentials it refers to.
+/// The overrides are applied, see `overrides`, and then the paths are expanded, see
+/// `SettingsEnv::expand_paths`.
+///
+/// # Arguments
+///
+/// * `file_path` - The settings file, `None` to read the settings only from the overrides.
+/// * `check_sources` - Also check that the folders to back up exist.
+///
+/// # Errors
+///
+/// * `ConfigParseError` if the file or an override isn't valid, see `parse_settings`
+///   and `apply_overrides`.
+/// * `InvalidConfigError` if a path can't be expanded or the settings aren't valid,
+///   see `SettingsEnv::problems`.
+/// * Any error while reading the file.
+pub fn read_settings(file_path: Option<&Path>, check_sources: bool) -> Result<SettingsEnv, Box<dyn std::error::Error>> {
+    let source = settings_source(file_path);
+    let settings = match file_path {
+        Some(file_path) => {
+            let contents = std::fs::read_to_string(file_path)?;
+            parse_settings(&contents, ConfigFormat::from_path(file_path), &source)?
+        }
+        None => parse_settings("{}", ConfigFormat::Json, &source)?,
+    };
+    let mut settings = apply_overrides(settings, &overrides())?;
+
+    // Paths are expanded before being validated, so that e.g. `~/Documents` is
+    // checked to exist.
+    let mut problems = settings.expand_paths();
+    problems.extend(settings.problems(check_sources));
+    if !problems.is_empty() {
+        return Err(InvalidConfigError { file: source, problems }.into());
+    }
+
+    Ok(settings)
+}
+
+// TODO: Make this function's example doc run?!
+/// Reads the settings file, together with the credentials it refers to.
+///
+/// # Returns
+///
+/// * A `SettingsEnv` struct of the settings with the email and password in plain text
+///
+/// # Errors
+///
+/// * `CredentialsError` if the credentials can't be read from where `credentials` points.
+/// * Any error returned by `read_settings`, or while decoding base64 credentials.
+///
+/// # Examples
+/// ```ignore
+/// let auth_info = read_auth_info(Some(Path::new("./settings.toml"))).unwrap();
+/// let SettingsEnv { email, password, .. } = auth_info;
+/// ```
+pub fn read_auth_info(file_path: Option<&Path>) -> Result<SettingsEnv, Box<dyn std::error::Error>> {
+    let settings = read_settings(file_path, false)?;
+    resolve_credentials(settings, &settings_source(file_path))
+}
+
+/// Reads the credentials settings already read refer to, e.g. after reading them
+/// with `read_settings` to check the folders to back up first.
+///
+/// # Arguments
+///
+/// * `auth_info` - The settings, as returned by `read_settings`.
+/// * `source` - Where the settings come from, see `settings_source`.
+///
+/// # Returns
+///
+/// * A `SettingsEnv` struct of the settings with the email and password in plain text
+///
+/// # Errors
+///
+/// * `CredentialsError` if the credentials can't be read from where `credentials` points.
+/// * Any error while decoding base64 credentials.
+pub fn resolve_credentials(auth_info: SettingsEnv, source: &str) -> Result<SettingsEnv, Box<dyn std::error::Error>> {
+    let mut mfa = auth_info.mfa.clone();
+    let (email, password) = match &auth_info.credentials {
+        Some(credentials) => {
+            let credentials = credentials.resolve()?;
+            if let (None, Some(totp_secret)) = (&mfa, credentials.totp_secret) {
+                mfa = Some(MfaSource::Totp(Secret::Value(totp_secret)));
+            }
+            (credentials.email, credentials.password)
+        }
+        None => {
+            log::warn!("Credentials are stored base64 encoded in {:?}, consider moving them into `credentials`.", source);
+
+            // Decode username and password
+            let email_bytes = base64::engine::general_purpose::STANDARD
+                .decode(&auth_info.email)?;
+
+            let password_bytes = base64::engine::general_purpose::STANDARD
+                .decode(&auth_info.password)?;
+
+            (String::from_utf8(email_bytes)?, String::from_utf8(password_bytes)?)
+        }
+    };
+
+    Ok(SettingsEnv {
+        email,
+        password,
+        mfa,
+        ..auth_info
+    })
+}
@@ -0,0 +1,262 @@
+//! Reading the MEGA credentials from where they are kept.
+//!
+//! The settings only hold references to the secrets: environment variables, files
+//! (e.g. container secrets), commands printing them (e.g. a password manager), or a
+//! file encrypted with a passphrase. The encrypted file is AES-256-GCM with a key
+//! derived from the passphrase by PBKDF2-HMAC-SHA256.
+//!
+//! Accounts with two-factor authentication need a code on every login, which is
+//! either generated from the TOTP secret, or asked for on the terminal.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::Engine;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{CredentialsError, FileExistsError};
+use crate::paths::{self, Env};
+use crate::{totp, utils};
+
+/// PBKDF2 rounds used for newly encrypted files, as recommended by OWASP for SHA-256.
+pub const KDF_ITERATIONS: u32 = 600_000;
+const SALT_LEN: usize = 16;
+
+/// Where a secret is read from.
+/// E.g.: `{"env": "MEGA_PASSWORD"}`, `{"file": "/run/secrets/mega"}`, `{"command": "pass show mega"}`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Secret {
+    /// The secret itself, only meant for values that aren't secret, like the email.
+    Value(String),
+    /// An environment variable.
+    Env(String),
+    /// A file holding the secret. A trailing newline is ignored.
+    File(PathBuf),
+    /// A shell command printing the secret. A trailing newline is ignored.
+    Command(String),
+}
+
+/// Where the MEGA credentials are read from.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum CredentialSource {
+    /// A file written by `backuprs credentials encrypt`.
+    Encrypted { encrypted_file: PathBuf, passphrase: Secret },
+    Separate { email: Secret, password: Secret },
+}
+
+/// How the two-factor authentication code is obtained when logging in.
+/// E.g.: `"prompt"`, `{"totp": {"env": "MEGA_TOTP_SECRET"}}`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MfaSource {
+    /// Ask for the code on the terminal, for manual runs.
+    Prompt,
+    /// Generate the code from the base32 TOTP secret shown when setting up 2FA.
+    Totp(Secret),
+}
+
+/// The decrypted contents of an encrypted credentials file.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct StoredCredentials {
+    pub email: String,
+    pub password: String,
+    /// Base32 encoded TOTP secret, used if the settings have no `mfa`.
+    #[serde(default)]
+    pub totp_secret: Option<String>,
+}
+
+/// An encrypted credentials file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EncryptedCredentials {
+    pub iterations: u32,
+    /// Base64 encoded salt of the key derivation.
+    pub salt: String,
+    /// Base64 encoded nonce of the encryption.
+    pub nonce: String,
+    /// Base64 encoded `StoredCredentials` as JSON, followed by the authentication tag.
+    pub ciphertext: String,
+}
+
+fn invalid(reason: String) -> CredentialsError {
+    CredentialsError { reason }
+}
+
+fn trim_newline(mut value: String) -> String {
+    while value.ends_with(['\n', '\r']) {
+        value.pop();
+    }
+    value
+}
+
+impl Secret {
+    /// Reads the secret.
+    ///
+    /// # Errors
+    ///
+    /// * `CredentialsError` if the variable isn't set, the file can't be read, or the
+    ///   command can't be run or fails.
+    pub fn resolve(&self) -> Result<String, CredentialsError> {
+        self.resolve_in(&paths::process_env)
+    }
+
+    /// Reads the secret like `resolve`, reading variables from the environment `env`.
+    pub fn resolve_in(&self, env: Env) -> Result<String, CredentialsError> {
+        let value = match self {
+            Secret::Value(value) => value.clone(),
+            Secret::Env(name) => env(name).and_then(|x| x.into_string().ok())
+                .ok_or_else(|| invalid(format!("Environment variable `{}` isn't set or isn't valid UTF-8.", name)))?,
+            Secret::File(path) => std::fs::read_to_string(path)
+                .map_err(|e| invalid(format!("Secret file {:?}: {}", path, e)))?,
+            Secret::Command(command) => run_command(command)?,
+        };
+
+        if value.trim().is_empty() {
+            return Err(invalid(format!("The secret read from {:?} is empty.", self)));
+        }
+        Ok(trim_newline(value))
+    }
+}
+
+/// Runs a command through the shell, returning what it printed.
+fn run_command(command: &str) -> Result<String, CredentialsError> {
+    let output = if cfg!(target_os = "windows") {
+        std::process::Command::new("cmd").args(["/C", command]).output()
+    } else {
+        std::process::Command::new("sh").args(["-c", command]).output()
+    }.map_err(|e| invalid(format!("Couldn't run `{}`: {}", command, e)))?;
+
+    if !output.status.success() {
+        return Err(invalid(format!(
+            "`{}` failed with {}: {}", command, output.status, String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    String::from_utf8(output.stdout).map_err(|_| invalid(format!("`{}` printed invalid UTF-8.", command)))
+}
+
+impl CredentialSource {
+    /// Reads the email and the password.
+    ///
+    /// # Errors
+    ///
+    /// * `CredentialsError` if a secret can't be read or the encrypted file can't be decrypted.
+    pub fn resolve(&self) -> Result<StoredCredentials, CredentialsError> {
+        self.resolve_in(&paths::process_env)
+    }
+
+    /// Reads the email and the password like `resolve`, reading variables from the
+    /// environment `env`.
+    pub fn resolve_in(&self, env: Env) -> Result<StoredCredentials, CredentialsError> {
+        match self {
+            CredentialSource::Separate { email, password } => Ok(StoredCredentials {
+                email: email.resolve_in(env)?,
+                password: password.resolve_in(env)?,
+                totp_secret: None,
+            }),
+            CredentialSource::Encrypted { encrypted_file, passphrase } => {
+                EncryptedCredentials::read_from_file(encrypted_file)?.decrypt(&passphrase.resolve_in(env)?)
+            }
+        }
+    }
+}
+
+impl MfaSource {
+    /// Returns the current two-factor authentication code.
+    ///
+    /// # Errors
+    ///
+    /// * `CredentialsError` if the secret can't be read or isn't valid base32, or the
+    ///   code can't be read from the terminal.
+    pub fn code(&self) -> Result<String, CredentialsError> {
+        match self {
+            MfaSource::Prompt => utils::prompt("MEGA two-factor authentication code: ", false)
+                .map(|x| String::from(x.trim()))
+                .map_err(|e| invalid(format!("Couldn't read the code: {}", e))),
+            MfaSource::Totp(secret) => totp::current_code(&secret.resolve()?),
+        }
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> Key<Aes256Gcm> {
+    pbkdf2::pbkdf2_hmac_array::<sha2::Sha256, 32>(passphrase.as_bytes(), salt, iterations).into()
+}
+
+impl EncryptedCredentials {
+    /// Encrypts the credentials with a key derived from `passphrase` in `iterations` rounds.
+    pub fn encrypt(credentials: &StoredCredentials, passphrase: &str, iterations: u32) -> Result<Self, CredentialsError> {
+        let mut salt = [0u8; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+        let plaintext = serde_json::to_vec(credentials).map_err(|e| invalid(e.to_string()))?;
+        let cipher = Aes256Gcm::new(&derive_key(passphrase, &salt, iterations));
+        let ciphertext = cipher.encrypt(&nonce, plaintext.as_slice())
+            .map_err(|_| invalid(String::from("Couldn't encrypt the credentials.")))?;
+
+        let b64 = base64::engine::general_purpose::STANDARD;
+        Ok(EncryptedCredentials {
+            iterations,
+            salt: b64.encode(salt),
+            nonce: b64.encode(nonce),
+            ciphertext: b64.encode(ciphertext),
+        })
+    }
+
+    /// Decrypts the credentials.
+    ///
+    /// # Errors
+    ///
+    /// * `CredentialsError` if the passphrase is wrong or the file was tampered with.
+    pub fn decrypt(&self, passphrase: &str) -> Result<StoredCredentials, CredentialsError> {
+        let b64 = base64::engine::general_purpose::STANDARD;
+        let decode = |field: &str, value: &str| b64.decode(value)
+            .map_err(|e| invalid(format!("Invalid `{}` in the encrypted credentials: {}", field, e)));
+
+        let salt = decode("salt", &self.salt)?;
+        let nonce = decode("nonce", &self.nonce)?;
+        let ciphertext = decode("ciphertext", &self.ciphertext)?;
+        if nonce.len() != 12 {
+            return Err(invalid(String::from("Invalid `nonce` in the encrypted credentials.")));
+        }
+
+        let cipher = Aes256Gcm::new(&derive_key(passphrase, &salt, self.iterations));
+        let plaintext = cipher.decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| invalid(String::from("Couldn't decrypt the credentials, the passphrase is wrong or the file is damaged.")))?;
+
+        serde_json::from_slice(&plaintext).map_err(|e| invalid(e.to_string()))
+    }
+
+    pub fn read_from_file(path: &Path) -> Result<Self, CredentialsError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| invalid(format!("Encrypted credentials {:?}: {}", path, e)))?;
+        serde_json::from_str(&contents)
+            .map_err(|e| invalid(format!("Encrypted credentials {:?}: {}", path, e)))
+    }
+
+    /// Writes the encrypted credentials to a new file, only readable by the user on Unix.
+    ///
+    /// # Errors
+    ///
+    /// * `FileExistsError` if a file already exists at `path`.
+    /// * Any error while writing the file.
+    pub fn write_to_file(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options.open(path).map_err(|e| -> Box<dyn std::error::Error> {
+            match e.kind() {
+                std::io::ErrorKind::AlreadyExists => FileExistsError { path: path.to_path_buf() }.into(),
+                _ => e.into(),
+            }
+        })?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        Ok(())
+    }
+}
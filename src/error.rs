@@ -3,6 +3,7 @@
 // but there must be an easier way to do this.
 // See other crates for more.
 
+use serde::Serialize;
 
 #[derive(Debug)]
 pub struct TarballExistsError {
@@ -23,6 +24,24 @@ impl std::fmt::Display for TarballExistsError {
     }
 }
 
+#[derive(Debug)]
+pub struct FileExistsError {
+    pub path: std::path::PathBuf
+}
+
+impl std::error::Error for FileExistsError {}
+
+impl std::fmt::Display for FileExistsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "`{}` already exists and wasn't overwritten. Move it away or choose a \
+            different path.",
+            self.path.display()
+        )
+    }
+}
+
 #[derive(Debug)]
 pub struct MEGAFileExistsError {
     pub file_name: String
@@ -41,3 +60,299 @@ impl std::fmt::Display for MEGAFileExistsError {
         )
     }
 }
+
+#[derive(Debug)]
+pub struct BackupFolderNotFoundError {
+    pub folder: String
+}
+
+impl std::error::Error for BackupFolderNotFoundError {}
+
+impl std::fmt::Display for BackupFolderNotFoundError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The backup folder `{}` doesn't exist in the cloud drive. Check `backup_folder` \
+            in the settings, or create the folder in MEGA.",
+            self.folder
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct BackupNotFoundError {
+    pub query: String
+}
+
+impl std::error::Error for BackupNotFoundError {}
+
+impl std::fmt::Display for BackupNotFoundError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Couldn't find a backup matching `{}` in the cloud drive. Try to specify \
+            the full file name, the date of the backup (YYYY-MM-DD) or `latest`.",
+            self.query
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct CorruptBackupError {
+    pub file_name: String,
+    pub reason: String
+}
+
+impl std::error::Error for CorruptBackupError {}
+
+impl std::fmt::Display for CorruptBackupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Backup `{}` is corrupt or truncated: {}",
+            self.file_name, self.reason
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct CheckFailedError {
+    pub failed: usize,
+    pub checked: usize
+}
+
+impl std::error::Error for CheckFailedError {}
+
+impl std::fmt::Display for CheckFailedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} out of {} checked backup(s) are corrupt, truncated or couldn't be downloaded.",
+            self.failed, self.checked
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidRetentionError {
+    pub reason: String
+}
+
+impl std::error::Error for InvalidRetentionError {}
+
+impl std::fmt::Display for InvalidRetentionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid retention policy: {}", self.reason)
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidNamingTemplateError {
+    pub template: String,
+    pub reason: String
+}
+
+impl std::error::Error for InvalidNamingTemplateError {}
+
+impl std::fmt::Display for InvalidNamingTemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid naming template {:?}: {}", self.template, self.reason)
+    }
+}
+
+#[derive(Debug)]
+pub struct CredentialsError {
+    pub reason: String
+}
+
+impl std::error::Error for CredentialsError {}
+
+impl std::fmt::Display for CredentialsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Couldn't read the credentials: {}", self.reason)
+    }
+}
+
+#[derive(Debug)]
+pub struct ConfigParseError {
+    pub file: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String
+}
+
+impl std::error::Error for ConfigParseError {}
+
+impl std::fmt::Display for ConfigParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, "{}:{}:{}: {}", self.file, line, column, self.message),
+            _ => write!(f, "{}: {}", self.file, self.message),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidConfigError {
+    pub file: String,
+    pub problems: Vec<String>
+}
+
+impl std::error::Error for InvalidConfigError {}
+
+impl std::fmt::Display for InvalidConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} has {} problem(s):", self.file, self.problems.len())?;
+        for problem in self.problems.iter() {
+            write!(f, "\n  * {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownJobError {
+    /// The job asked for, `None` if none was but the settings have several.
+    pub job: Option<String>,
+    pub jobs: Vec<String>
+}
+
+impl std::error::Error for UnknownJobError {}
+
+impl std::fmt::Display for UnknownJobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.job {
+            Some(job) => write!(f, "There is no job named {:?}, the jobs are: {}.", job, self.jobs.join(", ")),
+            None => write!(f, "Choose one of the jobs with `--job`: {}.", self.jobs.join(", ")),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct JobsFailedError {
+    /// Names of the failed jobs.
+    pub failed: Vec<String>,
+    pub run: usize
+}
+
+impl std::error::Error for JobsFailedError {}
+
+impl std::fmt::Display for JobsFailedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} out of {} job(s) failed: {}.", self.failed.len(), self.run, self.failed.join(", "))
+    }
+}
+
+/// Files were restored, but some of them don't match the manifest of the backup.
+#[derive(Debug)]
+pub struct CorruptFilesRestoredError {
+    pub archive: String,
+    pub corrupt: usize
+}
+
+impl std::error::Error for CorruptFilesRestoredError {}
+
+impl std::fmt::Display for CorruptFilesRestoredError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} file(s) restored from {} don't match its manifest.", self.corrupt, self.archive)
+    }
+}
+
+#[derive(Debug)]
+pub struct SettingsNotFoundError {
+    /// Where the settings were looked for.
+    pub searched: Vec<std::path::PathBuf>
+}
+
+impl std::error::Error for SettingsNotFoundError {}
+
+impl std::fmt::Display for SettingsNotFoundError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No settings file found, looked for:")?;
+        for file in self.searched.iter() {
+            write!(f, "\n  * {}", file.display())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct UsageError {
+    pub reason: String
+}
+
+impl std::error::Error for UsageError {}
+
+impl std::fmt::Display for UsageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+/// Returns a code identifying the kind of an error, which, unlike the messages, is
+/// kept stable for scripts reading the `--format json` output.
+/// E.g.: `BackupNotFoundError` ----> "backup_not_found"
+pub fn error_code(e: &(dyn std::error::Error + 'static)) -> &'static str {
+    if e.is::<TarballExistsError>() || e.is::<FileExistsError>() {
+        "file_exists"
+    } else if e.is::<MEGAFileExistsError>() {
+        "remote_file_exists"
+    } else if e.is::<BackupFolderNotFoundError>() {
+        "backup_folder_not_found"
+    } else if e.is::<BackupNotFoundError>() {
+        "backup_not_found"
+    } else if e.is::<CorruptBackupError>() {
+        "corrupt_backup"
+    } else if e.is::<CheckFailedError>() {
+        "check_failed"
+    } else if e.is::<InvalidRetentionError>() {
+        "invalid_retention"
+    } else if e.is::<InvalidNamingTemplateError>() {
+        "invalid_naming_template"
+    } else if e.is::<CredentialsError>() {
+        "credentials"
+    } else if e.is::<ConfigParseError>() {
+        "config_parse"
+    } else if e.is::<InvalidConfigError>() {
+        "invalid_config"
+    } else if e.is::<UnknownJobError>() {
+        "unknown_job"
+    } else if e.is::<JobsFailedError>() {
+        "jobs_failed"
+    } else if e.is::<CorruptFilesRestoredError>() {
+        "corrupt_files_restored"
+    } else if e.is::<SettingsNotFoundError>() {
+        "settings_not_found"
+    } else if e.is::<UsageError>() {
+        "usage"
+    } else if e.is::<glob::PatternError>() {
+        "invalid_pattern"
+    } else if e.is::<mega::Error>() {
+        "mega"
+    } else if e.is::<std::io::Error>() {
+        "io"
+    } else {
+        "other"
+    }
+}
+
+/// An error as printed by `--format json`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ErrorReport {
+    /// See `error_code`.
+    pub code: &'static str,
+    pub message: String,
+    /// Every problem found in the settings, for `invalid_config`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub problems: Vec<String>,
+}
+
+impl ErrorReport {
+    pub fn new(e: &(dyn std::error::Error + 'static)) -> Self {
+        ErrorReport {
+            code: error_code(e),
+            message: e.to_string(),
+            problems: e.downcast_ref::<InvalidConfigError>().map(|x| x.problems.clone()).unwrap_or_default(),
+        }
+    }
+}
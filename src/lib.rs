@@ -1,25 +1,56 @@
 //! **B**asic **A**utomated **C**loud **K**eeper for **U**ltimate **P**ersistence
 //! aka. BACKUP.rs
 
-use std::{fs::File, os::windows::fs::MetadataExt, path::Path};
+use std::{fs::File, path::Path};
 use flate2::Compression;
 use flate2::write::GzEncoder;
 use chrono;
 use mega::Node;
-use tokio_util::compat::TokioAsyncReadCompatExt;
-use utils::SettingsEnv;
+use tokio::io::AsyncWriteExt;
+use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+use tokio_util::io::SyncIoBridge;
+use config::SettingsEnv;
 use log::{info, error, debug, warn};
+use walker::DirWalker;
+use credentials::MfaSource;
+use manifest::{Manifest, ManifestEntry};
+use naming::{BackupName, CollisionPolicy, NamingTemplate};
+use pins::Pins;
+use retention::RetentionPolicy;
+
+pub use check::CheckSelection;
+pub use restore::{ConflictPolicy, RestoreOptions};
+pub use snapshot::OutputFormat;
 
 mod utils;
-mod error;
+pub mod error;
+mod check;
+mod config;
+mod credentials;
+mod manifest;
+#[cfg(all(target_os = "linux", feature = "fuse"))]
+mod mount;
+mod naming;
+mod paths;
+mod pins;
+mod plan;
+mod restore;
+mod retention;
+mod snapshot;
+mod summary;
+mod totp;
+pub mod walker;
 
-const SETTINGS_FILE: &str = "./settings.json";
 
 struct BackupClient {
     mega_client: mega::Client,
     dropped: bool,
     backup_folder: String,
-    backup_node: Option<Node>
+    backup_node: Option<Node>,
+    /// Template telling which files in `backup_folder` are backups.
+    naming: NamingTemplate,
+    /// Profile whose backups are listed.
+    profile: String
 }
 
 impl BackupClient {
@@ -30,7 +61,9 @@ impl BackupClient {
             mega_client: client,
             dropped: false,
             backup_folder: String::from("/Root/Backups"),
-            backup_node: None
+            backup_node: None,
+            naming: NamingTemplate::default(),
+            profile: String::from(naming::DEFAULT_PROFILE)
         }
     }
 
@@ -41,10 +74,39 @@ impl BackupClient {
             mega_client: client,
             dropped: false,
             backup_folder: backup_folder,
-            backup_node: None
+            backup_node: None,
+            naming: NamingTemplate::default(),
+            profile: String::from(naming::DEFAULT_PROFILE)
         }
     }
 
+    /// Sets the template of the backups' names, which tells which files are backups.
+    pub fn with_naming(mut self, naming: NamingTemplate) -> Self {
+        self.naming = naming;
+        self
+    }
+
+    /// Sets the profile whose backups are listed and made, i.e. the name of the job.
+    pub fn with_profile(mut self, profile: &str) -> Self {
+        self.profile = String::from(profile);
+        self
+    }
+
+    /// Switches to the backup folder and profile of another job, once logged in.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an issue fetching the nodes from the MEGA client.
+    pub async fn use_job(&mut self, job: &config::Job) -> Result<(), Box<dyn std::error::Error>> {
+        self.backup_folder = job.backup_folder.clone();
+        self.profile = job.name.clone();
+
+        let nodes = self.mega_client.fetch_own_nodes().await?;
+        self.backup_node = nodes.get_node_by_path(&self.backup_folder).cloned();
+
+        Ok(())
+    }
+
     /// Logs into the MEGA service using the provided credentials.
     ///
     /// # Arguments
@@ -97,12 +159,12 @@ impl BackupClient {
         }
     }
 
-    /// Checks for obsolete backup nodes in the client's `backup_node` based on the specified criteria.
+    /// Checks for obsolete backup nodes in the client's `backup_node` based on the retention policy.
     ///
     /// # Arguments
     ///
-    /// * `max_backups`: The maximum number of backups to keep. If the total number of backup nodes
-    ///   exceeds this limit, the function considers the oldest nodes as obsolete.
+    /// * `policy`: Rules of which backups to keep. Backups not kept by any of the rules
+    ///   are considered obsolete. Why each backup is kept or not is logged.
     ///
     /// # Returns
     ///
@@ -113,47 +175,443 @@ impl BackupClient {
     /// # Errors
     ///
     /// * Returns an error if there is an issue fetching the nodes from the MEGA client.
-    /// * Returns an error if `self.backup_node` is None.
-    pub async fn find_obsolete_nodes(&self, max_backups: usize) -> Result<Option<Vec<Node>>, Box<dyn std::error::Error>> {
-        info!("Checking if there are more than {:?} backups.", max_backups);
+    /// * `BackupFolderNotFoundError` if `self.backup_node` is None.
+    /// * `InvalidRetentionError` if the policy isn't valid.
+    pub async fn find_obsolete_nodes(&self, policy: &RetentionPolicy) -> Result<Option<Vec<Node>>, Box<dyn std::error::Error>> {
+        info!("Checking which backups to keep under {:?}.", policy);
+        let mut obsolete_nodes = Vec::new();
+        for (node, snapshot) in self.snapshots(policy).await? {
+            if snapshot.prune {
+                info!("Removing {:?}: {}", node.name(), snapshot.reasons.join(", "));
+                obsolete_nodes.push(node);
+            } else {
+                debug!("Keeping {:?}: {}", node.name(), snapshot.reasons.join(", "));
+            }
+        }
+        if !obsolete_nodes.is_empty() {
+            info!("Found {:?} obsolete node(s).", obsolete_nodes.len());
+            Ok(Some(obsolete_nodes))
+        } else {
+            info!("Not found any obsolete nodes.");
+            Ok(None)
+        }
+    }
+
+    /// Lists the backups stored in the client's `backup_node`, marking the pinned ones
+    /// and the ones not kept by the retention policy.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing the backup nodes and their snapshots, oldest first.
+    ///
+    /// # Errors
+    ///
+    /// * Returns an error if there is an issue fetching the nodes or the storage quota.
+    /// * `InvalidRetentionError` if the policy isn't valid.
+    pub async fn snapshots(&self, policy: &RetentionPolicy) -> Result<Vec<(Node, snapshot::Snapshot)>, Box<dyn std::error::Error>> {
+        let backup_nodes = self.list_backups().await?;
+
+        let quota = match policy.max_quota_percent {
+            Some(_) => Some(self.mega_client.get_storage_quotas().await?),
+            None => None,
+        };
+
+        let mut snapshots = self.snapshots_of(&backup_nodes).await?;
+        snapshot::mark_obsolete(&mut snapshots, policy, quota.as_ref())?;
+
+        Ok(backup_nodes.into_iter().zip(snapshots).collect())
+    }
+
+    /// Returns the snapshots of backup nodes, with their pins.
+    async fn snapshots_of(&self, backup_nodes: &[Node]) -> Result<Vec<snapshot::Snapshot>, Box<dyn std::error::Error>> {
+        let pins = self.download_pins().await?;
+
+        Ok(backup_nodes.iter()
+            .map(|node| snapshot::Snapshot {
+                created_at: self.backup_time(node),
+                ..snapshot::Snapshot::from_node(node).with_pins(&pins)
+            })
+            .collect())
+    }
+
+    /// Finds the backups to delete before uploading a new backup, so that the new one
+    /// fits into the size budget of the policy. Only the size budget is applied here,
+    /// the other rules are applied by `find_obsolete_nodes` once the upload succeeded.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - Rules of which backups to keep.
+    /// * `new_backup` - Name and size in bytes of the backup about to be uploaded.
+    /// * `replaced` - Backup the new one replaces, which is deleted after the upload
+    ///   and so isn't counted.
+    ///
+    /// # Errors
+    ///
+    /// * Returns an error if there is an issue fetching the nodes or the storage quota.
+    pub async fn find_nodes_over_budget(&self, policy: &RetentionPolicy, new_backup: (&str, u64), replaced: Option<&Node>) -> Result<Vec<Node>, Box<dyn std::error::Error>> {
+        if !policy.has_budget() {
+            return Ok(Vec::new());
+        }
+
+        let (name, size) = new_backup;
+        let (backup_nodes, replaced_nodes): (Vec<Node>, Vec<Node>) = self.list_backups().await?.into_iter()
+            .partition(|node| replaced.is_none_or(|x| x.handle() != node.handle()));
+
+        let quota = match policy.max_quota_percent {
+            Some(_) => {
+                let mut quota = self.mega_client.get_storage_quotas().await?;
+                let replaced_size: u64 = replaced_nodes.iter().map(|x| x.size()).sum();
+                quota.memory_used = (quota.memory_used + size).saturating_sub(replaced_size);
+                Some(quota)
+            }
+            None => None,
+        };
+
+        let mut snapshots = self.snapshots_of(&backup_nodes).await?;
+        // The new backup is the newest, which is never removed to meet the budget.
+        snapshots.push(snapshot::Snapshot {
+            name: String::from(name),
+            size,
+            created_at: Some(chrono::Utc::now()),
+            prune: false,
+            reasons: Vec::new(),
+            pinned: false,
+            tag: None,
+            note: None,
+        });
+        snapshot::mark_over_budget(&mut snapshots, policy, quota.as_ref());
+
+        Ok(backup_nodes.into_iter().zip(snapshots)
+            .filter(|(_, snapshot)| snapshot.prune)
+            .map(|(node, snapshot)| {
+                info!("Removing {:?} to make room for the new backup: {}", node.name(), snapshot.reasons.join(", "));
+                node
+            })
+            .collect())
+    }
+
+    /// Lists the backups stored in the client's `backup_node`.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing the backup nodes sorted by their creation date,
+    /// oldest first.
+    ///
+    /// # Errors
+    ///
+    /// * Returns an error if there is an issue fetching the nodes from the MEGA client.
+    /// * `BackupFolderNotFoundError` if `self.backup_node` is None.
+    pub async fn list_backups(&self) -> Result<Vec<Node>, Box<dyn std::error::Error>> {
+        let backup_folder_node = self.backup_folder_node()?;
         let nodes = self.mega_client.fetch_own_nodes().await?;
         
         let mut backup_nodes: Vec<Node> = nodes.into_iter()
         .filter(|node| {
-            node.parent() == Some(self.backup_node.as_ref().expect("Backup node must be already defined to find obsolete nodes.").handle())
-            && node.name().contains(".tar.gz") 
-            && node.name().contains("backup")
+            node.parent() == Some(backup_folder_node.handle())
+            && node.kind() == mega::NodeKind::File
+            && self.naming.is_backup_of(node.name(), &self.profile)
         })
         .collect();
 
-        if backup_nodes.len() > max_backups {
-            backup_nodes.sort_by_key(|x| { x.created_at() });
-    
-            let no_of_obsolete_nodes = backup_nodes.len() - max_backups;
-            info!("Found {:?} obsolete node(s).", no_of_obsolete_nodes);
-    
-            Ok(Some(backup_nodes.into_iter().take(no_of_obsolete_nodes).collect()))
+        // Backups started within the same second are told apart by their sequence number.
+        backup_nodes.sort_by_key(|x| {
+            (self.backup_time(x), self.naming.parse(x.name()).map(|name| name.seq))
+        });
+
+        Ok(backup_nodes)
+    }
+
+    /// Returns the name of a new backup started at `timestamp`, numbered after the
+    /// backups of the same profile and host made on the same day.
+    ///
+    /// # Arguments
+    ///
+    /// * `timestamp` - When the backup was started.
+    /// * `on_collision` - What to do if a file with the name already exists, either
+    ///   in the backup folder or in the working directory.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing the name, and the existing backup to delete
+    /// before uploading the new one if it is to be replaced.
+    ///
+    /// # Errors
+    ///
+    /// * `MEGAFileExistsError` or `TarballExistsError` if the name is taken and
+    ///   `on_collision` is `Fail`, or is `NewSequence` but the template has no `{seq}`.
+    /// * Any error returned by `list_backups`.
+    pub async fn next_backup_name(&self, timestamp: chrono::DateTime<chrono::Utc>, on_collision: CollisionPolicy) -> Result<(String, Option<Node>), Box<dyn std::error::Error>> {
+        let hostname = naming::sanitize(&utils::hostname());
+        let same_day = self.list_backups().await?.iter()
+            .filter_map(|node| self.naming.parse(node.name()))
+            .filter(|name| {
+                name.hostname.as_deref() == Some(hostname.as_str())
+                && name.timestamp.map(|x| x.date_naive()) == Some(timestamp.date_naive())
+            })
+            .map(|name| name.seq)
+            .max();
+
+        let mut name = BackupName {
+            profile: Some(self.profile.clone()),
+            hostname: Some(hostname),
+            timestamp: Some(timestamp),
+            seq: same_day.map_or(1, |x| x + 1),
+            format: String::from("tar.gz"),
+        };
+
+        loop {
+            let file_name = self.naming.render(&name);
+            let existing = self.find_in_backup_folder(&file_name).await?;
+            let local_path = paths::cache_dir().join(&file_name);
+            let exists_locally = local_path.try_exists()?;
+
+            if existing.is_none() && !exists_locally {
+                return Ok((file_name, None));
+            }
+
+            match on_collision {
+                CollisionPolicy::NewSequence if self.naming.has_seq() => {
+                    debug!("{:?} already exists, trying the next sequence number.", file_name);
+                    name.seq += 1;
+                }
+                CollisionPolicy::Replace => {
+                    warn!("{:?} already exists, it will be replaced.", file_name);
+                    if exists_locally {
+                        std::fs::remove_file(&local_path)?;
+                    }
+                    return Ok((file_name, existing));
+                }
+                _ if existing.is_some() => return Err(error::MEGAFileExistsError{ file_name }.into()),
+                _ => return Err(error::TarballExistsError{ file_name }.into()),
+            }
+        }
+    }
 
+    /// Returns when a backup was made: the timestamp in its name, or if its name
+    /// doesn't hold one, when it was uploaded.
+    pub fn backup_time(&self, node: &Node) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.naming.parse(node.name())
+            .and_then(|name| name.timestamp)
+            .or_else(|| node.created_at())
+    }
+
+    /// Finds a single backup in the client's `backup_node`.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - Either `latest`, a date in `YYYY-MM-DD` format, or the exact file name
+    ///   of the backup. If there are more backups for a date, the newest one is returned.
+    ///
+    /// # Errors
+    ///
+    /// * `BackupNotFoundError` if there is no backup matching `query`.
+    /// * Any error returned by `list_backups`.
+    pub async fn find_backup(&self, query: &str) -> Result<Node, Box<dyn std::error::Error>> {
+        let backup_nodes = self.list_backups().await?;
+
+        let found = if query == "latest" {
+            backup_nodes.last()
+        } else if let Ok(date) = chrono::NaiveDate::parse_from_str(query, "%Y-%m-%d") {
+            backup_nodes.iter().rev().find(|node| {
+                node.name() == format!("backup{}.tar.gz", query)
+                || self.backup_time(node).map(|x| x.with_timezone(&chrono::Local).date_naive()) == Some(date)
+            })
         } else {
-            info!("Not found any obsolete nodes.");
-            Ok(None)
+            backup_nodes.iter().find(|node| node.name() == query)
+        };
+
+        match found {
+            Some(node) => Ok(node.clone()),
+            None => Err(error::BackupNotFoundError{ query: String::from(query) }.into())
+        }
+    }
+
+    /// Downloads a file from MEGA to the given local path.
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - The node of the file to be downloaded.
+    /// * `file_name` - Local path where the file is saved.
+    ///
+    /// # Errors
+    ///
+    /// * `TarballExistsError` if a file already exists at `file_name`.
+    /// * `CorruptBackupError` if the size of the downloaded file doesn't match the node's size.
+    /// * Any error that occurs during the download or while writing the file.
+    pub async fn download_file(&self, node: &Node, file_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if Path::new(file_name).try_exists()? {
+            return Err(error::TarballExistsError{ file_name: String::from(file_name) }.into());
         }
+
+        let mut file = tokio::fs::File::create(file_name).await?.compat_write();
+        self.mega_client.download_node(node, &mut file).await?;
+
+        // Wait for the pending writes, otherwise the file might still be incomplete.
+        let mut file = file.into_inner();
+        file.flush().await?;
+
+        let size = file.metadata().await?.len();
+        if size != node.size() {
+            return Err(error::CorruptBackupError{
+                file_name: String::from(file_name),
+                reason: format!("downloaded {} bytes out of {}", size, node.size())
+            }.into());
+        }
+
+        Ok(())
     }
 
-    /// Removes all nodes that are specified as an argument.
+    /// Removes all nodes that are specified as an argument, together with their indices.
     /// 
     /// # Arguments
     /// 
     /// * `obsolete_nodes` - Vector of nodes that must be deleted.
     pub async fn remove_obsolete_nodes(&self, obsolete_nodes: Vec<Node>) -> Result<(), Box<dyn std::error::Error>> {
+        let nodes = self.mega_client.fetch_own_nodes().await?;
+
         for node in obsolete_nodes.iter() {
             info!("Deleting node {:?}...", node.name());
             self.mega_client.delete_node(node).await?;
+
+            let index_name = manifest::index_name(node.name());
+            let index_node = nodes.iter().find(|x| {
+                x.name() == index_name && x.parent() == node.parent()
+            });
+            if let Some(index_node) = index_node {
+                info!("Deleting node {:?}...", index_node.name());
+                self.mega_client.delete_node(index_node).await?;
+            }
         }
 
         Ok(())
     }
 
+    /// Downloads the index that was uploaded next to the backup `backup_node`.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing `None` if the backup has no index (e.g. it was
+    /// created by an older version).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an issue during the download or while parsing the index.
+    pub async fn download_manifest(&self, backup_node: &Node) -> Result<Option<Manifest>, Box<dyn std::error::Error>> {
+        let nodes = self.mega_client.fetch_own_nodes().await?;
+        let index_name = manifest::index_name(backup_node.name());
+
+        let index_node = nodes.iter()
+            .find(|x| x.name() == index_name && x.parent() == backup_node.parent());
+        let Some(index_node) = index_node else {
+            return Ok(None);
+        };
+
+        let mut buffer: Vec<u8> = Vec::with_capacity(index_node.size() as usize);
+        self.mega_client.download_node(index_node, &mut buffer).await?;
+
+        Ok(Some(Manifest::read_from(buffer.as_slice())?))
+    }
+
+    /// Returns the client's `backup_node`.
+    ///
+    /// # Errors
+    ///
+    /// * `BackupFolderNotFoundError` if the backup folder doesn't exist in MEGA.
+    fn backup_folder_node(&self) -> Result<&Node, error::BackupFolderNotFoundError> {
+        self.backup_node.as_ref().ok_or_else(|| error::BackupFolderNotFoundError { folder: self.backup_folder.clone() })
+    }
+
+    /// Returns the file node named `file_name` directly inside the client's `backup_node`.
+    async fn find_in_backup_folder(&self, file_name: &str) -> Result<Option<Node>, Box<dyn std::error::Error>> {
+        let backup_folder_node = self.backup_folder_node()?;
+        let nodes = self.mega_client.fetch_own_nodes().await?;
+
+        Ok(nodes.into_iter().find(|node| {
+            node.name() == file_name &&
+            node.kind() == mega::NodeKind::File &&
+            node.parent() == Some(backup_folder_node.handle())
+        }))
+    }
+
+    /// Downloads the pinned backups of the backup folder.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing no pins if nothing was pinned yet.
+    pub async fn download_pins(&self) -> Result<Pins, Box<dyn std::error::Error>> {
+        let Some(pins_node) = self.find_in_backup_folder(pins::PINS_FILE_NAME).await? else {
+            return Ok(Pins::default());
+        };
+
+        let mut buffer: Vec<u8> = Vec::with_capacity(pins_node.size() as usize);
+        self.mega_client.download_node(&pins_node, &mut buffer).await?;
+
+        Ok(Pins::from_slice(&buffer)?)
+    }
+
+    /// Replaces the pins file in the backup folder with `pins`. The old file is only
+    /// deleted once the new one is uploaded, so the pins are never lost.
+    pub async fn upload_pins(&self, pins: &Pins) -> Result<(), Box<dyn std::error::Error>> {
+        let cache_dir = paths::cache_dir();
+        std::fs::create_dir_all(&cache_dir)?;
+        let file_name = cache_dir.join(pins::PINS_FILE_NAME).to_string_lossy().to_string();
+        std::fs::write(&file_name, pins.to_vec()?)?;
+
+        let result = async {
+            let pins_node = self.find_in_backup_folder(pins::PINS_FILE_NAME).await?;
+            self.upload_file_replacing(&file_name, pins_node.as_ref()).await
+        }.await;
+
+        std::fs::remove_file(&file_name)?;
+        result
+    }
+
+    /// Streams a file from MEGA into `read`, which is run on a blocking thread while
+    /// the file is being downloaded, so the file is never saved to disk.
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - The node of the file to be downloaded.
+    /// * `read` - Reads the file from a synchronous reader. It must read the file to its
+    ///   end, otherwise the download can't finish.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of `read` if it fails, otherwise the error of the download.
+    pub async fn stream_file<T, F>(&self, node: &Node, read: F) -> Result<T, Box<dyn std::error::Error>>
+    where
+        T: Send + 'static,
+        F: FnOnce(SyncIoBridge<tokio::io::DuplexStream>) -> std::io::Result<T> + Send + 'static,
+    {
+        let (writer, reader) = tokio::io::duplex(1048576);
+        let reader = SyncIoBridge::new(reader);
+        let reading = tokio::task::spawn_blocking(move || read(reader));
+
+        let (downloaded, read) = tokio::join!(
+            self.mega_client.download_node(node, writer.compat_write()),
+            reading
+        );
+
+        // The reader's error is the more telling one, since a failed
+        // read also breaks the download's pipe.
+        let read = read??;
+        downloaded?;
+
+        Ok(read)
+    }
+
+    /// Returns the manifest of a backup, either from its index, or if the backup has
+    /// no index, by streaming the archive and reading its headers.
+    pub async fn read_manifest(&self, backup_node: &Node) -> Result<Manifest, Box<dyn std::error::Error>> {
+        if let Some(manifest) = self.download_manifest(backup_node).await? {
+            return Ok(manifest);
+        }
+
+        warn!("Backup {:?} has no index, reading the archive instead.", backup_node.name());
+        let archive = String::from(backup_node.name());
+        self.stream_file(backup_node, move |reader| Manifest::from_archive(reader, &archive)).await
+    }
+
     /// Uploads a file to the client's MEGA backup folder node.
     ///
     /// # Arguments
@@ -175,15 +633,29 @@ impl BackupClient {
     /// Panics if the file name cannot be converted to a valid UTF-8 string or if there is an issue with
     /// fetching own nodes or getting file metadata.
     pub async fn upload_file(&self, file_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.upload_file_replacing(file_name, None).await
+    }
+
+    /// Uploads a file like `upload_file`, but next to `replaced`, the file of the same
+    /// name it replaces. The old file is only deleted, together with its index if it is
+    /// a backup, once the new one is uploaded, so a failed upload doesn't lose both.
+    ///
+    /// # Errors
+    ///
+    /// * `MEGAFileExistsError` if a file other than `replaced` has the same name.
+    /// * Any error while uploading the file or deleting `replaced`.
+    async fn upload_file_replacing(&self, file_name: &str, replaced: Option<&Node>) -> Result<(), Box<dyn std::error::Error>> {
         if let Some(dest_folder_node) = &self.backup_node {
             let nodes = self.mega_client.fetch_own_nodes().await?;
-            let file_name = Path::new(file_name).file_name().unwrap().to_str().unwrap();
+            let file_path = file_name;
+            let file_name = Path::new(file_path).file_name().unwrap().to_str().unwrap();
     
             // Check if a file with the same name is already uploaded in the same folder.
             let file_nodes : Vec<_> = nodes.iter().filter(|&node| { 
                 node.name() == file_name && 
                 node.kind() == mega::NodeKind::File && 
-                node.parent() == Some(dest_folder_node.handle())
+                node.parent() == Some(dest_folder_node.handle()) &&
+                replaced.is_none_or(|x| x.handle() != node.handle())
             }).collect();
     
             // If there is a file with the same name in the same folder, return an error.
@@ -192,7 +664,7 @@ impl BackupClient {
             }
     
             // Open file and read size to specify the length of the progress bar.
-            let file = tokio::fs::File::open(file_name).await?;
+            let file = tokio::fs::File::open(file_path).await?;
             let size = file.metadata().await?.len();
     
             self.mega_client.upload_node(
@@ -203,6 +675,11 @@ impl BackupClient {
                 mega::LastModified::Now,
             ).await?;
 
+            if let Some(replaced) = replaced {
+                info!("Replacing {:?}...", replaced.name());
+                self.remove_obsolete_nodes(vec![replaced.clone()]).await?;
+            }
+
             Ok(())
         } else {
             warn!("Tried to upload a file while there was no backup node specified!");
@@ -250,15 +727,19 @@ impl Drop for BackupClient {
 /// * `ignore_folders` - An optional vector of strings containing folder names to be ignored
 ///                      during the tarball creation process.
 /// 
+/// # Returns
+///
+/// Returns the `Manifest` of the files added to the tarball.
+///
 /// # Errors
 ///
-/// This function returns a `Result<(), Box<dyn std::error::Error>>`. Possible error variants
+/// This function returns a `Result<Manifest, Box<dyn std::error::Error>>`. Possible error variants
 /// include:
 /// * `TarballExistsError` - Returned if the specified tarball file already exists.
 /// * Any error that occurs during file operations, such as file creation, reading, or appending
 ///   to the tarball.
 ///
-fn create_tarball_from_dirs(dirs: Vec<String>, file_name: &str, max_file_mb: u64, ignore_folders: Option<Vec<String>>) -> Result<(), Box<dyn std::error::Error>> {
+fn create_tarball_from_dirs(dirs: Vec<String>, file_name: &str, max_file_mb: u64, ignore_folders: Option<Vec<String>>) -> Result<Manifest, Box<dyn std::error::Error>> {
     // Check if file already exists.
     match Path::new(file_name).try_exists() {
         Ok(true) => return Err(error::TarballExistsError{file_name: String::from(file_name)}.into()),
@@ -269,149 +750,897 @@ fn create_tarball_from_dirs(dirs: Vec<String>, file_name: &str, max_file_mb: u64
     // Create the archive file.
     let tar_gz = std::fs::File::create(file_name)?;
     let enc = GzEncoder::new(tar_gz, Compression::best());
-    let mut tar = tar::Builder::new(enc);
+    // Counting the uncompressed bytes, so that the manifest can tell
+    // where the contents of each file start inside the tar.
+    let mut tar = tar::Builder::new(utils::CountingWriter::new(enc));
+    let archive_name = Path::new(file_name).file_name().map(|x| x.to_string_lossy().to_string()).unwrap_or_default();
+    let mut manifest = Manifest::new(&archive_name);
 
     for dir_path in dirs.iter() {
-        let dir_contents = get_dir_contents(dir_path, &ignore_folders)?;
-
-        for node_path in dir_contents.iter() {
-            // Open file that will be later appended to the tar.
-            let mut f = File::open(&node_path)?;
-            
-            let file_size_bytes = f.metadata().unwrap().file_size();
-            let bytes_in_mb = 1048576;
-            let file_size_mb = file_size_bytes / bytes_in_mb;
-            if file_size_mb > max_file_mb {
+        // Files are streamed from the walker as they are found, so the archiving
+        // starts right away and the whole tree never has to be kept in memory.
+        let dir_contents = DirWalker::new(dir_path)
+            .ignore_folders(ignore_folders.clone())
+            .walk();
+
+        for entry in dir_contents {
+            let entry = entry?;
+            let node_path = entry.path.to_string_lossy();
+
+            let file_size_mb = entry.size / 1048576;
+            if plan::exceeds_size_cap(entry.size, max_file_mb) {
                 debug!("File of {:?} MB is ignored: {:?}", file_size_mb, node_path);
                 continue;
             }
-            
+
+            // Open file that will be later appended to the tar.
+            let mut f = File::open(&entry.path)?;
+
             debug!("Adding file ({:?} MB) to tarball: {:?}", file_size_mb, node_path);
             // Convert absolute path to relative path from `dir_path`.
             // E.g.: C:\\Users\\username\\Documents\\My\\Path\\backup_folder\\Makefile"
-            // ----> "backup_folder\\Makefile"
-            let backup_abs_folder_path = format!("{}\\", dir_path);
-            let backup_folder_name = dir_path.split("\\").last().unwrap();
-            let relative_path = format!("{}\\{}", backup_folder_name, node_path.trim_start_matches(&backup_abs_folder_path));
-
-            tar.append_file(Path::new(&relative_path), &mut f)?;
-
+            // ----> "backup_folder/Makefile"
+            let relative_path = restore::archive_path(dir_path, &entry.path);
+
+            let metadata = f.metadata()?;
+            let mut header = tar::Header::new_gnu();
+            header.set_metadata(&metadata);
+            // Hashing the file while it is being added, so it is read only once.
+            let mut hashing_reader = utils::HashingReader::new(&mut f);
+            tar.append_data(&mut header, Path::new(&relative_path), &mut hashing_reader)?;
+
+            // The file's contents are followed by padding up to the next 512 byte block.
+            let padded_size = metadata.len().div_ceil(512) * 512;
+            manifest.entries.push(ManifestEntry {
+                path: relative_path,
+                size: metadata.len(),
+                modified: metadata.modified().ok().map(chrono::DateTime::from),
+                offset: Some(tar.get_ref().count() - padded_size),
+                sha256: Some(hashing_reader.hex_digest()),
+            });
         }
     }
 
+    // Storing the manifest in the archive as well, so `check` can verify
+    // every file without relying on the index uploaded next to it.
+    let manifest_json = serde_json::to_vec(&manifest)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest_json.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(chrono::Utc::now().timestamp() as u64);
+    tar.append_data(&mut header, manifest::EMBEDDED_MANIFEST_PATH, manifest_json.as_slice())?;
+
     let _ = tar.finish();
 
-    Ok(())
+    Ok(manifest)
 }
 
-/// Recursively retrieves the contents (files and subdirectories' files) of the specified directory,
-/// excluding those listed in the optional `ignore_folders` vector.
-/// 
+/// Returns the two-factor authentication code to log in with, if the account needs one.
+fn mfa_code(mfa: &Option<MfaSource>) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    Ok(mfa.as_ref().map(|x| x.code()).transpose()?)
+}
+
+/// Reads the settings from `file`, instead of looking for them in the working
+/// directory and the config folder. `BACKUPRS_CONFIG` is ignored then.
+pub fn use_settings_file(file: std::path::PathBuf) {
+    config::use_settings_file(file);
+}
+
+/// Returns the folder the log is written to, e.g. `~/.local/state/backuprs`.
+pub fn state_dir() -> std::path::PathBuf {
+    paths::state_dir()
+}
+
+/// Reads the settings and logs in to MEGA, in the backup folder of a single job.
+///
 /// # Arguments
-/// 
-/// * `dir` - A string representing the path to the directory whose contents are to be retrieved.
-/// * `ignore_folders` - An optional vector of strings containing folder names to be ignored
-///                      during the retrieval process.
-/// 
+///
+/// * `job` - Name of the job, may only be `None` if there is just one.
+///
 /// # Errors
-/// 
-/// Possible error variants include any errors that may occur during directory traversal or metadata retrieval.
-/// 
-/// # Returns
-/// 
-/// Returns a `Result` with a vector of strings holding the absolute path of the found files, or an error on failure.
-fn get_dir_contents(dir: &str, ignore_folders: &Option<Vec<String>>) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    let dir_contents = Path::new(&dir).read_dir()?;
-
-    let mut nodes_to_save: Vec<String> = Vec::new();
-
-    for node in dir_contents {
-        let node = node?;
-        if node.metadata()?.is_dir() {
-            if let Some(folders) = ignore_folders {
-                if folders.contains(&node.file_name().to_string_lossy().to_string()) {
-                    continue;
-                }
-            }
+///
+/// * `UnknownJobError` if the job doesn't exist, or none was chosen out of several.
+/// * Any error while reading the settings or logging in.
+async fn login_for_job(job: Option<&str>) -> Result<(BackupClient, config::Job), Box<dyn std::error::Error>> {
+    let settings = config::read_auth_info(config::find_settings_file()?.as_deref())?;
+    let job = settings.job(job)?;
 
-            let mut node_contents = get_dir_contents(node.path().as_os_str().to_str().unwrap(), ignore_folders)?;
-            nodes_to_save.append(&mut node_contents);
-        } else {
-            nodes_to_save.push(node.path().to_string_lossy().to_string())
-        }
-    }
+    let SettingsEnv { 
+        email: email_decoded, password: pass_decoded, mfa, naming, ..
+    } = settings;
+
+    let mut client = BackupClient::new(job.backup_folder.clone()).with_naming(naming).with_profile(&job.name);
 
-    Ok(nodes_to_save)
+    client.login(&email_decoded, &pass_decoded, mfa_code(&mfa)?.as_deref()).await?;
+
+    Ok((client, job))
 }
 
+/// Backs up the folders of the chosen jobs, one after the other, and prunes their
+/// old backups. Prints a summary of every job once they are done.
+///
+/// # Arguments
+///
+/// * `job_names` - Names of the jobs to run, every job if it is empty.
+/// * `format` - Whether to print the summaries as a table or a JSON document.
+///
+/// # Errors
+///
+/// * `UnknownJobError` if there is no job with one of the names.
+/// * `JobsFailedError` if any of the jobs failed. The other jobs are still run, and
+///   why each job failed is in its summary.
+/// * Any error while reading the settings or logging in.
 #[tokio::main]
-pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
+pub async fn run(job_names: &[String], format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    let settings_file = config::find_settings_file()?;
+    // Missing folders are better found before reading the credentials and logging in
+    // than halfway through the backup.
+    let settings = config::read_settings(settings_file.as_deref(), true)?;
+    let settings = config::resolve_credentials(settings, &config::settings_source(settings_file.as_deref()))?;
+    let jobs = settings.select_jobs(job_names)?;
+
     let SettingsEnv { 
-        email: email_decoded, password: pass_decoded, dirs_to_backup, dirs_to_ignore
-    } = utils::read_auth_info(SETTINGS_FILE)?;
+        email: email_decoded, password: pass_decoded, mfa, naming, on_collision, ..
+    } = settings;
+
+    let mut client = BackupClient::new(jobs[0].backup_folder.clone()).with_naming(naming);
+
+    client.login(&email_decoded, &pass_decoded, mfa_code(&mfa)?.as_deref()).await?;
+
+    let mut summaries = Vec::new();
+    for job in jobs.iter() {
+        info!("Running job {:?}...", job.name);
+        let mut summary = summary::JobSummary::new(&job.name);
+        let result = match client.use_job(job).await {
+            Ok(()) => run_job(&client, job, on_collision, &mut summary).await,
+            Err(e) => Err(e)
+        };
+        match result {
+            Ok(()) => info!("Job {:?} finished successfully.", job.name),
+            Err(e) => {
+                error!("Job {:?} failed: {:?}", job.name, e);
+                summary.error = Some(error::ErrorReport::new(e.as_ref()));
+            }
+        }
+        summaries.push(summary);
+    }
 
-    // Set archive's file name related to current date.
-    let today_date = format!("{}", chrono::offset::Local::now().format("%Y-%m-%d"));
-    let file_name = format!("backup{}.tar.gz", today_date);
+    client.try_logout().await;
+    summary::print_summaries(&summaries, format)?;
 
-    info!("Creating tarball from dirs:");
-    dirs_to_backup.iter().for_each(|x| { info!("\t{}", x) });
+    let failed: Vec<_> = summaries.into_iter().filter(|x| !x.is_ok()).map(|x| x.job).collect();
+    if !failed.is_empty() {
+        return Err(error::JobsFailedError{ failed, run: jobs.len() }.into());
+    }
 
-    create_tarball_from_dirs(dirs_to_backup, &file_name, 512, Some(dirs_to_ignore))?;
-    info!("Created tarball successfully.");
-    info!("Uploading file to MEGA.");
+    Ok(())
+}
 
-    let mfa: Option<&str> = None;
+/// Prints what the chosen jobs would back up, the files left out and the estimated
+/// size of the archives, without making them or logging in to MEGA.
+///
+/// # Arguments
+///
+/// * `job_names` - Names of the jobs, every job if it is empty.
+/// * `format` - Whether to print a listing of the files or a JSON document.
+///
+/// # Errors
+///
+/// * `UnknownJobError` if there is no job with one of the names.
+/// * Any error returned by `validate_config`, or while walking the folders.
+pub fn backup_dry_run(job_names: &[String], format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    let settings_file = config::find_settings_file()?;
+    let settings = config::read_settings(settings_file.as_deref(), true)?;
+    let jobs = settings.select_jobs(job_names)?;
+
+    let plans = jobs.iter().map(plan::plan_job).collect::<Result<Vec<_>, _>>()?;
+    plan::print_plans(&plans, format)
+}
 
-    let mut client = BackupClient::new(String::from("/Root/Backups"));
+/// Backs up the folders of a job to the client's backup folder, and prunes the job's
+/// old backups. The client must already be logged in and using the job.
+///
+/// `summary` is filled in as the job goes, so that if it fails, e.g. while pruning, the
+/// summary still tells what was already done.
+async fn run_job(client: &BackupClient, job: &config::Job, on_collision: CollisionPolicy, summary: &mut summary::JobSummary) -> Result<(), Box<dyn std::error::Error>> {
+    let (archive_name, replaced_node) = client.next_backup_name(chrono::Utc::now(), on_collision).await?;
+    // The archive is built away from the working directory, which is wherever cron or
+    // systemd happens to start the backup.
+    let cache_dir = paths::cache_dir();
+    std::fs::create_dir_all(&cache_dir)?;
+    let file_name = cache_dir.join(&archive_name).to_string_lossy().to_string();
+
+    info!("Creating tarball {:?} from dirs:", file_name);
+    job.dirs_to_backup.iter().for_each(|x| { info!("\t{}", x) });
+
+    let manifest = create_tarball_from_dirs(job.dirs_to_backup.clone(), &file_name, job.max_file_mb, Some(job.dirs_to_ignore.clone()))?;
+    info!("Created tarball successfully.");
+    summary.files = manifest.entries.len();
+    summary.size = manifest.entries.iter().map(|x| x.size).sum();
+    summary.archive_size = std::fs::metadata(&file_name)?.len();
+    let index_name = manifest::index_name(&file_name);
+    manifest.write_to_file(&index_name)?;
+    info!("Uploading file to MEGA.");
 
-    client.login(&email_decoded, &pass_decoded, mfa).await?;
+    let uploaded = async {
+        // On a nearly full account the upload would fail before anything is pruned, so
+        // room is made for the new backup first.
+        let new_backup = (archive_name.as_str(), summary.archive_size);
+        let over_budget = client.find_nodes_over_budget(&job.retention, new_backup, replaced_node.as_ref()).await?;
+        if !over_budget.is_empty() {
+            summary.pruned = over_budget.iter().map(|x| String::from(x.name())).collect();
+            client.remove_obsolete_nodes(over_budget).await?;
+        }
+        client.upload_file_replacing(&file_name, replaced_node.as_ref()).await
+    }.await;
 
-    match client.upload_file(&file_name).await {
+    match uploaded {
         Ok(()) => (),
         Err(e) => {
             // Cleanup before returning error to main.
             error!("Error encountered in `upload_file`, starting cleanup...");
-            error!("Trying to log out...");
-            client.try_logout().await;
-            error!("Removing archive file...");
-            std::fs::remove_file(&file_name)?;
-            error!("Successfully removed archive file...");
+            remove_local_files(&[&file_name, &index_name]);
             return Err(e);
         }
     };
 
     info!("Uploaded file successfully.");
+    summary.archive = Some(archive_name);
+    summary.replaced = replaced_node.map(|x| String::from(x.name()));
+
+    // The backup itself is already safe, so a missing index is not worth failing for;
+    // only browsing the backup without downloading it won't be possible.
+    match client.upload_file(&index_name).await {
+        Ok(()) => info!("Uploaded index successfully."),
+        Err(e) => error!("Couldn't upload index {:?}: {:?}", index_name, e)
+    };
+
+    remove_local_files(&[&file_name, &index_name]);
 
-    let obsolete_nodes = client.find_obsolete_nodes(10).await?;
+    let obsolete_nodes = client.find_obsolete_nodes(&job.retention).await?;
 
     if let Some(nodes) = obsolete_nodes {
+        summary.pruned.extend(nodes.iter().map(|x| String::from(x.name())));
         client.remove_obsolete_nodes(nodes).await?;
     }
 
-    client.try_logout().await;
+    Ok(())
+}
 
+/// Removes the archive and the index of a job once they are uploaded or failed to be.
+/// Failures are only logged, since they shouldn't hide what happened to the backup.
+fn remove_local_files(files: &[&str]) {
     info!("Removing archive file...");
-    std::fs::remove_file(file_name)?;
-    info!("Successfully removed archive file...");
-
-    Ok(())
+    for file in files {
+        if let Err(e) = std::fs::remove_file(file) {
+            error!("Couldn't remove {:?}: {:?}", file, e);
+        }
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Downloads a backup from MEGA, verifies it and extracts it.
+///
+/// # Arguments
+///
+/// * `job` - Name of the job whose backups to use, may only be `None` if there is just one.
+/// * `options` - Which backup to restore and where to restore it. See `RestoreOptions`.
+/// * `format` - Whether to print a line or a JSON document about the restored files.
+///
+/// # Errors
+///
+/// * `BackupNotFoundError` if there is no backup matching `options.snapshot`.
+/// * `CorruptBackupError` if the downloaded archive is truncated or can't be decompressed.
+/// * `CorruptFilesRestoredError` if any file restored by `options.filters` doesn't
+///   match the archive's manifest, after the summary is printed.
+/// * Any error that occurs while logging in, downloading or extracting the archive.
+#[tokio::main]
+pub async fn restore(job: Option<&str>, options: RestoreOptions, format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut client, job) = login_for_job(job).await?;
+    let dirs_to_backup = job.dirs_to_backup;
+
+    let node = client.find_backup(&options.snapshot).await?;
+    let archive = String::from(node.name());
+
+    if !options.filters.is_empty() {
+        let filters = options.filters.iter()
+            .map(|filter| restore::PathFilter::parse(filter))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Only a few entries are needed, so instead of saving the whole archive first,
+        // the download is streamed straight into the extraction.
+        info!("Restoring {:?} matching {:?} from MEGA.", archive, options.filters);
+        let target = options.target.clone();
+        let restored = client.stream_file(&node, move |reader| {
+            restore::extract_archive(reader, target.as_deref(), &dirs_to_backup, &filters, options.conflict)
+        }).await;
+        client.try_logout().await;
+
+        // The files are written as they are downloaded, so they can only be checked
+        // against the manifest afterwards. The corrupt ones are already in the
+        // printed summary.
+        let restored = restored?;
+        restore::print_summary(&archive, &restored, format)?;
+        if restored.corrupt > 0 {
+            return Err(error::CorruptFilesRestoredError { archive, corrupt: restored.corrupt }.into());
+        }
+        return Ok(());
+    }
 
-    #[test]
-    fn retrieve_dir_contents() {
-        let expected_contents = vec![
-            String::from("src\\lib.rs"), 
-            String::from("src\\main.rs")
-        ];
+    let cache_dir = paths::cache_dir();
+    std::fs::create_dir_all(&cache_dir)?;
+    let file_name = cache_dir.join(&archive).to_string_lossy().to_string();
 
-        let contents = get_dir_contents("src", &None).unwrap();
-        
-        assert!(expected_contents.iter().all(|item| contents.contains(item)));
+    info!("Downloading {:?} from MEGA.", file_name);
+    if let Err(e) = client.download_file(&node, &file_name).await {
+        error!("Error encountered in `download_file`, starting cleanup...");
+        client.try_logout().await;
+        if Path::new(&file_name).exists() {
+            std::fs::remove_file(&file_name)?;
+        }
+        return Err(e);
+    }
+
+    client.try_logout().await;
+
+    info!("Verifying {:?}...", file_name);
+    let result = restore::verify_tarball(&file_name).and_then(|no_of_entries| {
+        info!("Archive is valid, found {:?} entries.", no_of_entries);
+        restore::extract_tarball(&file_name, options.target.as_deref(), &dirs_to_backup, &[], options.conflict)
+    });
+
+    info!("Removing archive file...");
+    std::fs::remove_file(&file_name)?;
+    info!("Successfully removed archive file...");
+
+    restore::print_summary(&archive, &result?, format)
+}
+
+/// Lists the backups stored in MEGA, together with the ones that would be
+/// deleted by the next pruning.
+///
+/// # Arguments
+///
+/// * `job` - Name of the job whose backups to use, may only be `None` if there is just one.
+/// * `format` - Whether to print a table or a JSON document.
+/// * `tag` - List only the backups pinned with this tag.
+/// * `pinned` - List only the pinned backups.
+#[tokio::main]
+pub async fn list(job: Option<&str>, format: OutputFormat, tag: Option<String>, pinned: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut client, job) = login_for_job(job).await?;
+    let snapshots = client.snapshots(&job.retention).await;
+    client.try_logout().await;
+
+    let snapshots: Vec<_> = snapshots?.into_iter()
+        .map(|(_, snapshot)| snapshot)
+        .filter(|snapshot| !pinned || snapshot.pinned)
+        .filter(|snapshot| tag.is_none() || snapshot.tag == tag)
+        .collect();
+
+    snapshot::print_snapshots(&snapshots, format)
+}
+
+/// Pins a backup, so that it is never pruned.
+///
+/// # Arguments
+///
+/// * `job` - Name of the job whose backups to use, may only be `None` if there is just one.
+/// * `snapshot` - Name of the backup, its date (`YYYY-MM-DD`) or `latest`.
+/// * `tag` - Label to list the backup by, e.g. `before-migration`.
+/// * `note` - Why the backup is pinned.
+/// * `format` - Whether to print a line or a JSON document about the pinned backup.
+///
+/// # Errors
+///
+/// * `BackupNotFoundError` if there is no backup matching `snapshot`.
+/// * Any error that occurs while logging in or updating the pins.
+#[tokio::main]
+pub async fn pin(job: Option<&str>, snapshot: &str, tag: Option<String>, note: Option<String>, format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    let change = update_pins(job, snapshot, |pins, archive| {
+        pins.pin(archive, tag.clone(), note.clone());
+        pins::PinChange { archive: String::from(archive), pinned: true, changed: true, tag, note }
+    }).await?;
+    pins::print_change(&change, format)
+}
+
+/// Unpins a backup, so that it is pruned again like any other backup.
+///
+/// # Arguments
+///
+/// * `job` - Name of the job whose backups to use, may only be `None` if there is just one.
+/// * `snapshot` - Name of the backup, its date (`YYYY-MM-DD`) or `latest`.
+/// * `format` - Whether to print a line or a JSON document about the unpinned backup.
+#[tokio::main]
+pub async fn unpin(job: Option<&str>, snapshot: &str, format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    let change = update_pins(job, snapshot, |pins, archive| {
+        let changed = pins.unpin(archive);
+        pins::PinChange { archive: String::from(archive), pinned: false, changed, tag: None, note: None }
+    }).await?;
+    pins::print_change(&change, format)
+}
+
+/// Downloads the pins, updates them for the backup matching `snapshot` and uploads them again.
+async fn update_pins<F: FnOnce(&mut Pins, &str) -> pins::PinChange>(job: Option<&str>, snapshot: &str, update: F) -> Result<pins::PinChange, Box<dyn std::error::Error>> {
+    let (mut client, _) = login_for_job(job).await?;
+
+    let result = async {
+        let node = client.find_backup(snapshot).await?;
+        let mut pins = client.download_pins().await?;
+        let change = update(&mut pins, node.name());
+        client.upload_pins(&pins).await?;
+        Ok(change)
+    }.await;
+
+    client.try_logout().await;
+    result
+}
+
+/// Deletes the backups not kept by the retention policy, together with their indexes.
+///
+/// # Arguments
+///
+/// * `job` - Name of the job whose backups to use, may only be `None` if there is just one.
+/// * `dry_run` - Only print the backups that would be deleted, without deleting them.
+/// * `format` - Whether to print the deleted backups, or a JSON document of whether and
+///   why every backup is kept or deleted.
+///
+/// # Errors
+///
+/// * `InvalidRetentionError` if the configured retention policy isn't valid.
+/// * Any error that occurs while logging in, listing or deleting the backups.
+#[tokio::main]
+pub async fn prune(job: Option<&str>, dry_run: bool, format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    #[derive(serde::Serialize)]
+    struct PruneReport<'a> {
+        dry_run: bool,
+        snapshots: &'a [snapshot::Snapshot],
+    }
+
+    let (mut client, job) = login_for_job(job).await?;
+
+    let snapshots = match client.snapshots(&job.retention).await {
+        Ok(snapshots) => snapshots,
+        Err(e) => {
+            client.try_logout().await;
+            return Err(e);
+        }
+    };
+    let obsolete_nodes: Vec<_> = snapshots.iter()
+        .filter(|(_, snapshot)| snapshot.prune)
+        .map(|(node, _)| node.clone())
+        .collect();
+    let snapshots: Vec<_> = snapshots.into_iter().map(|(_, snapshot)| snapshot).collect();
+
+    let result = if dry_run || obsolete_nodes.is_empty() {
+        Ok(())
+    } else {
+        client.remove_obsolete_nodes(obsolete_nodes).await
+    };
+    client.try_logout().await;
+    result?;
+
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&PruneReport { dry_run, snapshots: &snapshots })?),
+        OutputFormat::Table => {
+            let pruned: Vec<_> = snapshots.iter().filter(|x| x.prune).collect();
+            for snapshot in pruned.iter() {
+                println!(
+                    "{} {}  ({})",
+                    if dry_run { "Would delete" } else { "Deleted" },
+                    snapshot.name,
+                    utils::format_size(snapshot.size)
+                );
+            }
+            let total: u64 = pruned.iter().map(|x| x.size).sum();
+            println!(
+                "{} {} backup(s), {} in total.",
+                if dry_run { "Would delete" } else { "Deleted" },
+                pruned.len(),
+                utils::format_size(total)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Asks for the MEGA credentials and a passphrase, and saves the credentials
+/// encrypted with the passphrase, to be referred to by `credentials.encrypted_file`.
+///
+/// # Arguments
+///
+/// * `output` - Where to save the encrypted credentials.
+/// * `format` - Whether to only log where the credentials were saved, or also print
+///   it as a JSON document.
+///
+/// # Errors
+///
+/// * `FileExistsError` if a file already exists at `output`.
+/// * `CredentialsError` if the passphrases don't match or are empty.
+/// * Any error while reading the input or writing the file.
+pub fn encrypt_credentials(output: &Path, format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    if output.try_exists()? {
+        return Err(error::FileExistsError{ path: output.to_path_buf() }.into());
+    }
+
+    let credentials = credentials::StoredCredentials {
+        email: utils::prompt("MEGA email: ", false)?,
+        password: utils::prompt("MEGA password: ", true)?,
+        totp_secret: Some(utils::prompt("TOTP secret (empty if 2FA is disabled): ", true)?)
+            .filter(|x| !x.trim().is_empty()),
+    };
+    let passphrase = utils::prompt("Passphrase: ", true)?;
+    if passphrase.is_empty() || passphrase != utils::prompt("Passphrase again: ", true)? {
+        return Err(error::CredentialsError{ reason: String::from("The passphrases are empty or don't match.") }.into());
+    }
+
+    credentials::EncryptedCredentials::encrypt(&credentials, &passphrase, credentials::KDF_ITERATIONS)?
+        .write_to_file(output)?;
+    info!("Saved encrypted credentials to {:?}.", output);
+    if format == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&serde_json::json!({ "output": output }))?);
+    }
+
+    Ok(())
+}
+
+/// Reads and validates the settings file, including whether the folders to back up
+/// exist, without reading the credentials or logging in.
+///
+/// # Arguments
+///
+/// * `format` - Whether to print a line or a JSON document naming the valid settings.
+///
+/// # Errors
+///
+/// * `SettingsNotFoundError` if there is no settings file.
+/// * `ConfigParseError` if the file isn't valid TOML, YAML or JSON, or doesn't match
+///   the expected settings.
+/// * `InvalidConfigError` listing every problem found in the settings.
+pub fn validate_config(format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    let settings_file = config::find_settings_file()?;
+    config::read_settings(settings_file.as_deref(), true)?;
+    let source = config::settings_source(settings_file.as_deref());
+
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&serde_json::json!({ "file": source, "valid": true }))?),
+        OutputFormat::Table => println!("{} is valid.", source),
+    }
+
+    Ok(())
+}
+
+/// Prints the settings in effect: the settings file with the overrides applied and the
+/// paths expanded. Secrets written in the settings are redacted.
+///
+/// # Arguments
+///
+/// * `format` - Whether to print TOML, preceded by where the settings come from, or a
+///   JSON document.
+///
+/// # Errors
+///
+/// * Any error returned by `validate_config`, except for missing folders to back up.
+pub fn show_config(format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    let settings_file = config::find_settings_file()?;
+    let settings = config::read_settings(settings_file.as_deref(), false)?.redacted();
+
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&settings)?),
+        OutputFormat::Table => {
+            println!("# Read from {}", config::settings_source(settings_file.as_deref()));
+            for item in config::overrides() {
+                println!("# Overridden by {}", item.source);
+            }
+            print!("{}", toml::to_string_pretty(&settings)?);
+        }
+    }
+
+    Ok(())
+}
+
+/// Overrides settings for this run, taking precedence over the environment and the
+/// settings file.
+///
+/// # Arguments
+///
+/// * `flags` - Values of the `--set` flags, e.g. `retention.keep_daily=7`.
+///
+/// # Errors
+///
+/// * `ConfigParseError` if a flag isn't `path=value`.
+pub fn use_setting_flags(flags: &[String]) -> Result<(), error::ConfigParseError> {
+    let overrides = flags.iter()
+        .map(|flag| config::Override::from_flag(flag).ok_or_else(|| error::ConfigParseError {
+            file: String::from("--set"),
+            line: None,
+            column: None,
+            message: format!("Expected `path=value`, e.g. `retention.keep_daily=7`, found {:?}.", flag),
+        }))
+        .collect::<Result<Vec<_>, _>>()?;
+    config::use_setting_flags(overrides);
+
+    Ok(())
+}
+
+/// Prints an error to stdout as a JSON document, with a stable code telling what kind
+/// of error it is, see `error::error_code`.
+/// E.g.: `{"error": {"code": "backup_not_found", "message": "Couldn't find..."}}`
+pub fn print_error(e: &(dyn std::error::Error + 'static)) -> Result<(), serde_json::Error> {
+    println!("{}", serde_json::to_string_pretty(&serde_json::json!({ "error": error::ErrorReport::new(e) }))?);
+    Ok(())
+}
+
+/// Reads the manifests of the given backups from MEGA.
+async fn read_manifests(job: Option<&str>, snapshots: &[&str]) -> Result<Vec<Manifest>, Box<dyn std::error::Error>> {
+    let (mut client, _) = login_for_job(job).await?;
+
+    let mut manifests = Vec::new();
+    for snapshot in snapshots {
+        let manifest = match client.find_backup(snapshot).await {
+            Ok(node) => client.read_manifest(&node).await,
+            Err(e) => Err(e)
+        };
+        match manifest {
+            Ok(manifest) => manifests.push(manifest),
+            Err(e) => {
+                client.try_logout().await;
+                return Err(e);
+            }
+        }
+    }
+
+    client.try_logout().await;
+
+    Ok(manifests)
+}
+
+/// Reads the manifest of a single backup from MEGA.
+async fn read_manifest(job: Option<&str>, snapshot: &str) -> Result<Manifest, Box<dyn std::error::Error>> {
+    let mut manifests = read_manifests(job, &[snapshot]).await?;
+    Ok(manifests.remove(0))
+}
+
+/// Lists a directory inside a backup, reading only the backup's index.
+///
+/// # Arguments
+///
+/// * `job` - Name of the job whose backups to use, may only be `None` if there is just one.
+/// * `snapshot` - Name of the backup, its date (`YYYY-MM-DD`) or `latest`.
+/// * `dir` - Directory inside the backup, e.g. `backup_folder/notes`. If it is empty,
+///   the backed up folders are listed.
+/// * `format` - Whether to print a table or a JSON document.
+#[tokio::main]
+pub async fn ls(job: Option<&str>, snapshot: &str, dir: &str, format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    let manifest = read_manifest(job, snapshot).await?;
+    manifest::print_listing(&manifest.list_dir(dir), format)
+}
+
+/// Finds files inside a backup by their path or a glob pattern, reading only the
+/// backup's index.
+///
+/// # Arguments
+///
+/// * `job` - Name of the job whose backups to use, may only be `None` if there is just one.
+/// * `snapshot` - Name of the backup, its date (`YYYY-MM-DD`) or `latest`.
+/// * `pattern` - Path (e.g. `backup_folder/notes`) or glob pattern (e.g. `*.txt`).
+/// * `format` - Whether to print a table or a JSON document.
+#[tokio::main]
+pub async fn find(job: Option<&str>, snapshot: &str, pattern: &str, format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    let filter = restore::PathFilter::parse(pattern)?;
+    let manifest = read_manifest(job, snapshot).await?;
+    manifest::print_entries(&manifest.find(&filter), format)
+}
+
+/// Compares two backups and prints the files that were added, removed or modified.
+///
+/// # Arguments
+///
+/// * `job` - Name of the job whose backups to use, may only be `None` if there is just one.
+/// * `old` - Name of the older backup, its date (`YYYY-MM-DD`) or `latest`.
+/// * `new` - Name of the newer backup, its date (`YYYY-MM-DD`) or `latest`.
+/// * `format` - Whether to print a table or a JSON document.
+#[tokio::main]
+pub async fn diff(job: Option<&str>, old: &str, new: &str, format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    let manifests = read_manifests(job, &[old, new]).await?;
+    manifest::print_changes(&manifest::diff(&manifests[0], &manifests[1]), format)
+}
+
+/// Downloads backups from MEGA and checks that they are complete and every file in
+/// them matches the checksums of the archive's embedded manifest.
+///
+/// # Arguments
+///
+/// * `job` - Name of the job whose backups to use, may only be `None` if there is just one.
+/// * `selection` - Which backups to check.
+/// * `format` - Whether to print a table or a JSON document.
+///
+/// # Errors
+///
+/// * `CheckFailedError` if any of the checked backups is corrupt, truncated or
+///   couldn't be downloaded.
+/// * `BackupNotFoundError` if a single backup was selected, but it doesn't exist.
+#[tokio::main]
+pub async fn check(job: Option<&str>, selection: CheckSelection, format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut client, _) = login_for_job(job).await?;
+
+    let backup_nodes = match &selection {
+        CheckSelection::Snapshot(query) => client.find_backup(query).await.map(|x| vec![x]),
+        CheckSelection::All => client.list_backups().await,
+        CheckSelection::Sample(size) => client.list_backups().await.map(|nodes| {
+            use rand::seq::SliceRandom;
+            nodes.choose_multiple(&mut rand::thread_rng(), *size).cloned().collect()
+        }),
+    };
+    let backup_nodes = match backup_nodes {
+        Ok(nodes) => nodes,
+        Err(e) => {
+            client.try_logout().await;
+            return Err(e);
+        }
+    };
+
+    let mut reports = Vec::new();
+    for node in backup_nodes.iter() {
+        info!("Checking {:?}...", node.name());
+        let archive = String::from(node.name());
+        let report = client.stream_file(node, move |reader| Ok(check::check_archive(reader, &archive))).await;
+
+        reports.push(report.unwrap_or_else(|e| {
+            let mut report = check::CheckReport::new(node.name());
+            report.problems.push(format!("Couldn't download the archive: {}", e));
+            report
+        }));
+    }
+
+    client.try_logout().await;
+
+    check::print_reports(&reports, format)?;
+
+    let failed = reports.iter().filter(|x| !x.is_ok()).count();
+    if failed > 0 {
+        return Err(error::CheckFailedError{ failed, checked: reports.len() }.into());
+    }
+
+    Ok(())
+}
+
+/// Mounts backups as a read-only filesystem. Blocks until it is unmounted
+/// (e.g. with `fusermount -u`).
+///
+/// # Arguments
+///
+/// * `job` - Name of the job whose backups to use, may only be `None` if there is just one.
+/// * `snapshot` - Name of the backup to mount, its date (`YYYY-MM-DD`) or `latest`.
+///   If it is `None`, every backup is mounted in a folder of its own.
+/// * `archive` - Local archive to mount instead of the backups stored in MEGA.
+/// * `mountpoint` - Existing, empty directory to mount the backups at.
+#[cfg(all(target_os = "linux", feature = "fuse"))]
+#[tokio::main]
+pub async fn mount(job: Option<&str>, snapshot: Option<String>, archive: Option<std::path::PathBuf>, mountpoint: std::path::PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+    use mount::{ArchiveSource, MountedSnapshot};
+
+    let (client, snapshots) = match archive {
+        Some(archive) => {
+            let name = archive.file_name().map(|x| x.to_string_lossy().to_string()).unwrap_or_default();
+            let manifest = Manifest::from_archive(File::open(&archive)?, &name)?;
+            (None, vec![MountedSnapshot { source: ArchiveSource::Local(archive), manifest }])
+        }
+        None => {
+            let (client, _) = login_for_job(job).await?;
+
+            let backup_nodes = match &snapshot {
+                Some(query) => vec![client.find_backup(query).await?],
+                None => client.list_backups().await?
+            };
+
+            let mut snapshots = Vec::new();
+            for node in backup_nodes {
+                let manifest = client.read_manifest(&node).await?;
+                snapshots.push(MountedSnapshot { source: ArchiveSource::Remote(Box::new(node)), manifest });
+            }
+
+            (Some(client), snapshots)
+        }
+    };
+
+    let cache_dir = paths::cache_dir().join(format!("mount-{}", std::process::id()));
+    std::fs::create_dir_all(&cache_dir)?;
+
+    let fs = mount::SnapshotFs::new(client, tokio::runtime::Handle::current(), snapshots, cache_dir);
+    let options = [fuser::MountOption::RO, fuser::MountOption::FSName(String::from("backuprs"))];
+
+    info!("Mounting backups at {:?}...", mountpoint);
+    // The filesystem blocks on downloads from within its callbacks, which
+    // is only allowed outside of the runtime's own threads.
+    tokio::task::spawn_blocking(move || fuser::mount2(fs, &mountpoint, &options)).await??;
+    info!("Unmounted backups.");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retrieve_dir_contents() {
+        let expected_contents = vec![
+            String::from("src\\lib.rs"), 
+            String::from("src\\main.rs")
+        ];
+
+        let contents: Vec<String> = DirWalker::new("src").walk()
+            .map(|entry| entry.unwrap().path.to_string_lossy().to_string())
+            .collect();
+        
+        assert!(expected_contents.iter().all(|item| contents.contains(item)));
+    }
+
+    #[test]
+    fn walk_is_deterministic() {
+        let walk = || -> Vec<walker::WalkEntry> {
+            DirWalker::new("src").threads(3).queue_bound(1).walk()
+                .map(|entry| entry.unwrap())
+                .collect()
+        };
+
+        let first = walk();
+        assert!(!first.is_empty());
+        assert_eq!(first, walk());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn walk_skips_symlinks() {
+        let base = std::env::temp_dir().join("backuprs-testsymlinks");
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(base.join("notes")).unwrap();
+        std::fs::write(base.join("notes").join("todo.txt"), "todo").unwrap();
+        // A link to a folder, which leads back to the walked root.
+        std::os::unix::fs::symlink(&base, base.join("notes").join("loop")).unwrap();
+        std::os::unix::fs::symlink(base.join("notes").join("todo.txt"), base.join("todo.txt")).unwrap();
+
+        let contents: Vec<std::path::PathBuf> = DirWalker::new(&base).walk()
+            .map(|entry| entry.unwrap().path)
+            .collect();
+        assert_eq!(contents, vec![base.join("notes").join("todo.txt")]);
+
+        let file_name = "testsymlinks.tar.gz";
+        let manifest = create_tarball_from_dirs(vec![base.to_string_lossy().to_string()], file_name, 512, None);
+        std::fs::remove_file(file_name).unwrap();
+        std::fs::remove_dir_all(&base).unwrap();
+        assert_eq!(manifest.unwrap().entries.len(), 1);
+    }
+
+    #[test]
+    fn plan_backup() {
+        let base = std::env::temp_dir().join("backuprs-testplan");
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(base.join("node_modules")).unwrap();
+        std::fs::write(base.join("notes.txt"), "backup ".repeat(2048)).unwrap();
+        std::fs::write(base.join("large.bin"), vec![0u8; 2 * 1048576]).unwrap();
+        std::fs::write(base.join("node_modules").join("index.js"), "ignored").unwrap();
+
+        let job = config::Job {
+            name: String::from("default"),
+            dirs_to_backup: vec![base.to_string_lossy().to_string()],
+            dirs_to_ignore: vec![String::from("node_modules")],
+            max_file_mb: 1,
+            backup_folder: String::from(config::DEFAULT_BACKUP_FOLDER),
+            retention: RetentionPolicy::default(),
+        };
+        let plan = plan::plan_job(&job).unwrap();
+
+        let path = |name: &str| base.join(name).to_string_lossy().to_string();
+        assert_eq!(plan.included, vec![plan::IncludedFile { path: path("notes.txt"), size: 7 * 2048 }]);
+        assert_eq!(plan.excluded, vec![
+            plan::ExcludedFile { path: path("large.bin"), size: 2 * 1048576, reason: plan::ExclusionReason::TooLarge },
+            plan::ExcludedFile { path: path("node_modules"), size: 0, reason: plan::ExclusionReason::IgnoredFolder },
+        ]);
+        assert_eq!(plan.total_size, 7 * 2048);
+        assert!(plan.estimated_compressed_size > 0 && plan.estimated_compressed_size < plan.total_size);
+
+        std::fs::remove_dir_all(&base).unwrap();
     }
 
     #[test]
@@ -421,7 +1650,8 @@ mod tests {
         // to build this binary.
         let dirs = vec![String::from("./src")];
         let file_name = "testarchive.tar.gz";
-        create_tarball_from_dirs(dirs, file_name, 512, None).unwrap();
+        let manifest = create_tarball_from_dirs(dirs, file_name, 512, None).unwrap();
+        assert!(manifest.entries.iter().any(|x| x.path == "src/lib.rs"));
 
         let file_path = Path::new(file_name);
 
@@ -432,11 +1662,881 @@ mod tests {
         assert!(!file_path.exists())
     }
 
+    #[test]
+    fn restore_tarball() {
+        let dirs = vec![String::from("src")];
+        let file_name = "testrestore.tar.gz";
+        let target = std::env::temp_dir().join("backuprs-testrestore");
+        create_tarball_from_dirs(dirs, file_name, 512, None).unwrap();
+
+        let no_of_entries = restore::verify_tarball(file_name).unwrap();
+        let summary = restore::extract_tarball(file_name, Some(&target), &[], &[], ConflictPolicy::Overwrite).unwrap();
+        assert_eq!(no_of_entries, summary.restored);
+
+        let restored = std::fs::read_to_string(target.join("src").join("lib.rs")).unwrap();
+        assert_eq!(restored, std::fs::read_to_string("src/lib.rs").unwrap());
+
+        // Existing files are handled by the conflict policy.
+        let restored_path = target.join("src").join("main.rs");
+        std::fs::write(&restored_path, "changed").unwrap();
+        let filters = vec![restore::PathFilter::parse("src\\main.rs").unwrap()];
+
+        let summary = restore::extract_tarball(file_name, Some(&target), &[], &filters, ConflictPolicy::Skip).unwrap();
+        assert_eq!((summary.restored, summary.skipped), (0, 1));
+        assert_eq!(std::fs::read_to_string(&restored_path).unwrap(), "changed");
+
+        // The file written above is newer than the archived one.
+        let summary = restore::extract_tarball(file_name, Some(&target), &[], &filters, ConflictPolicy::IfNewer).unwrap();
+        assert_eq!((summary.restored, summary.skipped), (0, 1));
+
+        let summary = restore::extract_tarball(file_name, Some(&target), &[], &filters, ConflictPolicy::Rename).unwrap();
+        assert_eq!((summary.restored, summary.renamed), (1, 1));
+        assert!(target.join("src").join("main (restored).rs").exists());
+        assert_eq!(std::fs::read_to_string(&restored_path).unwrap(), "changed");
+
+        let summary = restore::extract_tarball(file_name, Some(&target), &[], &filters, ConflictPolicy::Overwrite).unwrap();
+        assert_eq!(summary.restored, 1);
+        assert_eq!(std::fs::read_to_string(&restored_path).unwrap(), std::fs::read_to_string("src/main.rs").unwrap());
+
+        // Restoring only the matching entries.
+        std::fs::remove_dir_all(&target).unwrap();
+        let summary = restore::extract_tarball(file_name, Some(&target), &[], &filters, ConflictPolicy::Overwrite).unwrap();
+        assert_eq!(summary.restored, 1);
+        assert!(target.join("src").join("main.rs").exists());
+        assert!(!target.join("src").join("lib.rs").exists());
+
+        std::fs::remove_file(file_name).unwrap();
+        std::fs::remove_dir_all(target).unwrap();
+    }
+
+    #[test]
+    fn check_tarball() {
+        let file_name = "testcheck.tar.gz";
+        create_tarball_from_dirs(vec![String::from("src")], file_name, 512, None).unwrap();
+
+        let report = check::check_archive(std::fs::File::open(file_name).unwrap(), file_name);
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert!(report.entries > 0);
+        assert_eq!(report.entries, report.verified);
+
+        // Cutting off the end of the archive must be noticed.
+        let contents = std::fs::read(file_name).unwrap();
+        std::fs::remove_file(file_name).unwrap();
+        let report = check::check_archive(&contents[..contents.len() / 2], file_name);
+        assert!(!report.is_ok());
+    }
+
+    #[test]
+    fn sanitize_archive_paths() {
+        assert_eq!(restore::sanitize_archive_path("backup_folder/notes/todo.txt"), Some(vec!["backup_folder", "notes", "todo.txt"]));
+        assert_eq!(restore::sanitize_archive_path("backup_folder\\notes\\todo.txt"), Some(vec!["backup_folder", "notes", "todo.txt"]));
+        assert_eq!(restore::sanitize_archive_path("./backup_folder/todo.txt"), Some(vec!["backup_folder", "todo.txt"]));
+        assert_eq!(restore::sanitize_archive_path("backup_folder/../../etc/passwd"), None);
+        assert_eq!(restore::sanitize_archive_path("..\\Windows\\win.ini"), None);
+        assert_eq!(restore::sanitize_archive_path("/etc/passwd"), None);
+        assert_eq!(restore::sanitize_archive_path("backup_folder/C:/Windows/win.ini"), None);
+        assert_eq!(restore::sanitize_archive_path("backup_folder\\notes\\d:evil.txt"), None);
+        // Only Windows reads a `:` as an alternate data stream, and new archives only split on `/`.
+        if cfg!(target_os = "windows") {
+            assert_eq!(restore::sanitize_archive_path("backup_folder/todo.txt:stream"), None);
+        } else {
+            assert_eq!(restore::sanitize_archive_path("backup_folder/10:30 meeting.txt"), Some(vec!["backup_folder", "10:30 meeting.txt"]));
+        }
+        assert_eq!(restore::sanitize_archive_path("backup_folder/a\\b.txt"), Some(vec!["backup_folder", "a\\b.txt"]));
+        assert_eq!(restore::sanitize_archive_path("\\\\server\\share\\file"), None);
+        assert_eq!(restore::sanitize_archive_path("C:\\Windows\\win.ini"), None);
+
+        assert_eq!(restore::archive_path("C:\\Users\\username\\backup_folder", Path::new("C:\\Users\\username\\backup_folder")), "backup_folder");
+        assert_eq!(restore::archive_path("./src", &Path::new("./src").join("lib.rs")), "src/lib.rs");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restore_rejects_links() {
+        let base = std::env::temp_dir().join("backuprs-testlinks");
+        let _ = std::fs::remove_dir_all(&base);
+        let (target, outside) = (base.join("target"), base.join("outside"));
+        std::fs::create_dir_all(target.join("backup_folder")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, target.join("backup_folder").join("notes")).unwrap();
+
+        let mut tar = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        tar.append_link(&mut header, "backup_folder/link", &outside).unwrap();
+        for path in ["backup_folder/notes/todo.txt", "backup_folder/todo.txt"] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(4);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, path, "todo".as_bytes()).unwrap();
+        }
+        let archive = tar.into_inner().unwrap().finish().unwrap();
+
+        let summary = restore::extract_archive(archive.as_slice(), Some(&target), &[], &[], ConflictPolicy::Overwrite).unwrap();
+        assert_eq!((summary.restored, summary.rejected), (1, 2));
+        assert!(target.join("backup_folder").join("todo.txt").exists());
+        assert!(!target.join("backup_folder").join("link").exists());
+        assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restore_unusual_names() {
+        let target = std::env::temp_dir().join("backuprs-testnames");
+        let _ = std::fs::remove_dir_all(&target);
+
+        // Names like these are valid on Unix, and kept as they are by `archive_path`.
+        let mut tar = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for path in ["backup_folder/notes/10:30 meeting.txt", "backup_folder/a\\b.txt"] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(4);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, path, "todo".as_bytes()).unwrap();
+        }
+        let archive = tar.into_inner().unwrap().finish().unwrap();
+
+        let summary = restore::extract_archive(archive.as_slice(), Some(&target), &[], &[], ConflictPolicy::Overwrite).unwrap();
+        assert_eq!((summary.restored, summary.rejected), (2, 0));
+        assert!(target.join("backup_folder").join("notes").join("10:30 meeting.txt").is_file());
+        assert!(target.join("backup_folder").join("a\\b.txt").is_file());
+
+        std::fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[test]
+    fn restore_verifies_checksums() {
+        let target = std::env::temp_dir().join("backuprs-testverify");
+        let _ = std::fs::remove_dir_all(&target);
+
+        let mut manifest = Manifest::new("verify.tar.gz");
+        for (path, contents) in [("backup_folder/todo.txt", "todo"), ("backup_folder/done.txt", "done")] {
+            let mut hashing_reader = utils::HashingReader::new(contents.as_bytes());
+            std::io::copy(&mut hashing_reader, &mut std::io::sink()).unwrap();
+            manifest.entries.push(ManifestEntry {
+                path: String::from(path), size: 4, modified: None, offset: None, sha256: Some(hashing_reader.hex_digest()),
+            });
+        }
+        let embedded = serde_json::to_vec(&manifest).unwrap();
+
+        let mut tar = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        // The second file was damaged after the manifest was written.
+        for (path, contents) in [("backup_folder/todo.txt", "todo".as_bytes()), ("backup_folder/done.txt", "dome".as_bytes()), (manifest::EMBEDDED_MANIFEST_PATH, embedded.as_slice())] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, path, contents).unwrap();
+        }
+        let archive = tar.into_inner().unwrap().finish().unwrap();
+
+        let filters = [restore::PathFilter::parse("backup_folder").unwrap()];
+        let summary = restore::extract_archive(archive.as_slice(), Some(&target), &[], &filters, ConflictPolicy::Overwrite).unwrap();
+        assert_eq!((summary.restored, summary.corrupt), (2, 1));
+
+        std::fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[test]
+    fn restore_path_filters() {
+        let prefix = restore::PathFilter::parse("backup_folder\\notes").unwrap();
+        assert!(prefix.matches("backup_folder/notes"));
+        assert!(prefix.matches("backup_folder/notes/todo.txt"));
+        assert!(!prefix.matches("backup_folder/notes_old/todo.txt"));
+
+        let glob = restore::PathFilter::parse("backup_folder/*.txt").unwrap();
+        assert!(glob.matches("backup_folder/notes/todo.txt"));
+        assert!(!glob.matches("other_folder/todo.txt"));
+        assert!(!glob.matches("backup_folder/Makefile"));
+    }
+
+    #[test]
+    fn mark_obsolete_snapshots() {
+        let mut snapshots: Vec<_> = (1..=4).map(|day| snapshot::Snapshot {
+            name: format!("backup2024-01-0{}.tar.gz", day),
+            size: 1024,
+            created_at: chrono::DateTime::from_timestamp(1704103200 + day * 86400, 0),
+            prune: false,
+            reasons: Vec::new(),
+            pinned: false,
+            tag: None,
+            note: None,
+        }).collect();
+
+        let keep_last = |n| RetentionPolicy { keep_last: Some(n), ..Default::default() };
+
+        snapshot::mark_obsolete(&mut snapshots, &keep_last(3), None).unwrap();
+        let pruned: Vec<_> = snapshots.iter().map(|x| x.prune).collect();
+        assert_eq!(pruned, vec![true, false, false, false]);
+
+        snapshot::mark_obsolete(&mut snapshots, &keep_last(10), None).unwrap();
+        assert!(snapshots.iter().all(|x| !x.prune));
+
+        assert!(snapshot::mark_obsolete(&mut snapshots, &RetentionPolicy::default(), None).is_err());
+
+        // The oldest backups are removed until the rest fits into the budget, but never the newest one.
+        let budget = |n| RetentionPolicy { max_total_size: Some(n), ..keep_last(10) };
+
+        snapshot::mark_obsolete(&mut snapshots, &budget(2048), None).unwrap();
+        let pruned: Vec<_> = snapshots.iter().map(|x| x.prune).collect();
+        assert_eq!(pruned, vec![true, true, false, false]);
+
+        snapshot::mark_obsolete(&mut snapshots, &budget(10), None).unwrap();
+        let pruned: Vec<_> = snapshots.iter().map(|x| x.prune).collect();
+        assert_eq!(pruned, vec![true, true, true, false]);
+
+        // 3 KiB of other files and at most 50% of 10 KiB leaves 2 KiB for the backups.
+        let quota = mega::StorageQuotas { memory_used: 7 * 1024, memory_total: 10 * 1024 };
+        let quota_policy = RetentionPolicy { max_quota_percent: Some(50.0), ..Default::default() };
+        snapshot::mark_obsolete(&mut snapshots, &quota_policy, Some(&quota)).unwrap();
+        let pruned: Vec<_> = snapshots.iter().map(|x| x.prune).collect();
+        assert_eq!(pruned, vec![true, true, false, false]);
+        assert_eq!(snapshots[0].reasons, vec!["over the size budget of 2.0 KiB"]);
+
+        // Before uploading, only the budget is applied, the other rules wait for the upload.
+        snapshot::mark_over_budget(&mut snapshots, &RetentionPolicy { max_total_size: Some(3072), ..keep_last(1) }, None);
+        let pruned: Vec<_> = snapshots.iter().map(|x| x.prune).collect();
+        assert_eq!(pruned, vec![true, false, false, false]);
+
+        assert!(RetentionPolicy { max_quota_percent: Some(150.0), ..Default::default() }.validate().is_err());
+
+        // Pinned backups are kept by every rule and never count as over the budget.
+        let mut pins = pins::Pins::default();
+        pins.pin("backup2024-01-01.tar.gz", Some(String::from("before-migration")), None);
+        let pins = pins::Pins::from_slice(&pins.to_vec().unwrap()).unwrap();
+        let mut snapshots: Vec<_> = snapshots.into_iter().map(|x| x.with_pins(&pins)).collect();
+        assert_eq!(snapshots[0].tag.as_deref(), Some("before-migration"));
+
+        snapshot::mark_obsolete(&mut snapshots, &budget(2048), None).unwrap();
+        let pruned: Vec<_> = snapshots.iter().map(|x| x.prune).collect();
+        assert_eq!(pruned, vec![false, true, true, false]);
+        assert_eq!(snapshots[0].reasons[0], "pinned as \"before-migration\"");
+    }
+
+    #[test]
+    fn format_sizes() {
+        assert_eq!(utils::format_size(512), "512 B");
+        assert_eq!(utils::format_size(1536), "1.5 KiB");
+    }
+
+    #[test]
+    fn grandfather_father_son_retention() {
+        use chrono::TimeZone;
+
+        // Two backups a day at 01:00 and 13:00 local time, from 2023-01-01 to 2024-03-31.
+        let mut timestamps = Vec::new();
+        let mut day = chrono::NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+        while day <= chrono::NaiveDate::from_ymd_opt(2024, 3, 31).unwrap() {
+            for hour in [1, 13] {
+                let local = chrono::Local.from_local_datetime(&day.and_hms_opt(hour, 0, 0).unwrap()).unwrap();
+                timestamps.push(Some(local.with_timezone(&chrono::Utc)));
+            }
+            day = day.succ_opt().unwrap();
+        }
+        timestamps.push(None);
+
+        let policy = RetentionPolicy {
+            keep_last: Some(3),
+            keep_daily: Some(7),
+            keep_weekly: Some(4),
+            keep_monthly: Some(6),
+            keep_yearly: Some(2),
+            ..Default::default()
+        };
+        let decisions = policy.apply(&timestamps).unwrap();
+        let kept: Vec<String> = timestamps.iter().zip(decisions.iter())
+            .filter(|(_, decision)| decision.keep)
+            .filter_map(|(timestamp, _)| timestamp.map(|x| x.with_timezone(&chrono::Local).format("%Y-%m-%d %H").to_string()))
+            .collect();
+
+        assert_eq!(kept, vec![
+            "2023-10-31 13", "2023-11-30 13",                   // monthly
+            "2023-12-31 13",                                    // monthly, yearly
+            "2024-01-31 13",                                    // monthly
+            "2024-02-29 13",                                    // monthly
+            "2024-03-10 13",                                    // weekly
+            "2024-03-17 13",                                    // weekly
+            "2024-03-24 13",                                    // weekly
+            "2024-03-25 13", "2024-03-26 13", "2024-03-27 13",  // daily
+            "2024-03-28 13", "2024-03-29 13", "2024-03-30 13",  // daily
+            "2024-03-31 01",                                    // last
+            "2024-03-31 13",                                    // last, daily, weekly, monthly, yearly
+        ]);
+
+        // Backups without a date are never removed.
+        assert!(decisions.last().unwrap().keep);
+        assert_eq!(
+            decisions[decisions.len() - 2].reasons,
+            vec!["one of the last 3", "daily backup of 2024-03-31", "weekly backup of 2024-W13", "monthly backup of 2024-03", "yearly backup of 2024"]
+        );
+
+        let within = RetentionPolicy { keep_within: Some(String::from("1w")), ..Default::default() };
+        let kept = within.apply(&timestamps).unwrap().iter().filter(|x| x.keep).count();
+        assert_eq!(kept, 7 * 2 + 1 + 1);
+
+        assert!(retention::parse_duration("1y6m").is_ok());
+        assert!(retention::parse_duration("6").is_err());
+        assert!(retention::parse_duration("3x").is_err());
+        assert!(retention::parse_duration("99999999999y").is_err());
+        assert!(retention::parse_duration("9223372036854775807h").is_err());
+
+        // A duration reaching back before the earliest representable date can't be applied.
+        let within = RetentionPolicy { keep_within: Some(String::from("250000000y")), ..Default::default() };
+        assert!(within.validate().is_err());
+        assert!(within.apply(&[chrono::DateTime::from_timestamp(1704103200, 0)]).is_err());
+    }
+
+    #[test]
+    fn backup_names() {
+        let naming = NamingTemplate::default();
+        let timestamp = chrono::DateTime::from_timestamp(1704101400, 0);
+        let name = BackupName {
+            profile: Some(String::from("photos")),
+            hostname: Some(String::from("my-laptop")),
+            timestamp,
+            seq: 12,
+            format: String::from("tar.gz"),
+        };
+
+        let file_name = naming.render(&name);
+        assert_eq!(file_name, "backup-photos-my_laptop-20240101T093000Z-12.tar.gz");
+        assert_eq!(naming.parse(&file_name), Some(BackupName { hostname: Some(String::from("my_laptop")), ..name }));
+
+        // Names of older versions belong to the default profile.
+        let legacy = naming.parse("backup2024-01-01.tar.gz").unwrap();
+        assert_eq!((legacy.profile(), legacy.timestamp), (naming::DEFAULT_PROFILE, None));
+
+        for other in ["backup2024-01-01.index.json.gz", "backuprs-pins.json", "my-backup.tar.gz", "backup-photos-host-2024-1.tar.gz"] {
+            assert_eq!(naming.parse(other), None, "{}", other);
+        }
+
+        let custom = NamingTemplate::new("{hostname}_{timestamp}.{format}").unwrap();
+        let parsed = custom.parse("nas_20240101T093000Z.tar.gz").unwrap();
+        assert_eq!((parsed.hostname.as_deref(), parsed.timestamp, parsed.profile()), (Some("nas"), timestamp, "default"));
+
+        // Of the backups made on the same day, only the newest one is the daily backup.
+        let same_day: Vec<_> = ["backup-photos-nas-20240101T093000Z-1.tar.gz", "backup-photos-nas-20240101T093000Z-2.tar.gz", "backup-photos-nas-20240101T180000Z-3.tar.gz"]
+            .iter()
+            .map(|x| naming.parse(x).unwrap().timestamp)
+            .collect();
+        let daily = RetentionPolicy { keep_daily: Some(1), ..Default::default() };
+        let kept: Vec<_> = daily.apply(&same_day).unwrap().iter().map(|x| x.keep).collect();
+        assert_eq!(kept, vec![false, false, true]);
+        let kept: Vec<_> = daily.apply(&same_day[..2]).unwrap().iter().map(|x| x.keep).collect();
+        assert_eq!(kept, vec![false, true]);
+
+        for invalid in ["backup-{timestamp}", "{profile}{timestamp}.{format}", "{date}.{format}", "a/{timestamp}.{format}", "{timestamp.{format}"] {
+            assert!(NamingTemplate::new(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn backups_of_jobs() {
+        use config::{ConfigFormat, parse_settings};
+
+        let toml = r#"
+naming = "{hostname}_{timestamp}.{format}"
+credentials = { email = { value = "me@example.com" }, password = { value = "hunter2" } }
+
+[jobs.photos]
+dirs_to_backup = ["src"]
+backup_folder = "/Root/Photos"
+
+[jobs.documents]
+dirs_to_backup = ["src"]
+backup_folder = "/Root/Documents"
+"#;
+        let settings = parse_settings(toml, ConfigFormat::Toml, "settings.toml").unwrap();
+        assert!(settings.problems(false).is_empty());
+
+        // Each job has a folder of its own, so every backup in it is the job's.
+        for job in settings.jobs() {
+            assert!(settings.naming.is_backup_of("nas_20240101T093000Z.tar.gz", &job.name), "{}", job.name);
+            assert!(settings.naming.is_backup_of("backup2024-01-01.tar.gz", &job.name), "{}", job.name);
+            assert!(!settings.naming.is_backup_of("nas_20240101T093000Z.index.json.gz", &job.name), "{}", job.name);
+        }
+
+        // With `{profile}` in the names, only the job's own backups are.
+        let naming = NamingTemplate::default();
+        assert!(naming.is_backup_of("backup-photos-nas-20240101T093000Z-1.tar.gz", "photos"));
+        assert!(!naming.is_backup_of("backup-documents-nas-20240101T093000Z-1.tar.gz", "photos"));
+    }
+
+    #[test]
+    fn read_credentials() {
+        use credentials::{CredentialSource, EncryptedCredentials, Secret, StoredCredentials};
+
+        // Variables are read from an environment of the test's own, see `settings_discovery`.
+        let env = |name: &str| (name == "MEGA_EMAIL").then(|| std::ffi::OsString::from("me@example.com"));
+        std::fs::write("testsecret.txt", "hunter2\n").unwrap();
+
+        let separate = CredentialSource::Separate {
+            email: Secret::Env(String::from("MEGA_EMAIL")),
+            password: Secret::File(std::path::PathBuf::from("testsecret.txt")),
+        };
+        let expected = StoredCredentials { email: String::from("me@example.com"), password: String::from("hunter2"), totp_secret: None };
+        assert_eq!(separate.resolve_in(&env).unwrap(), expected);
+
+        assert_eq!(Secret::Command(String::from("echo hunter2")).resolve().unwrap(), "hunter2");
+        assert!(Secret::Env(String::from("UNSET")).resolve_in(&env).is_err());
+        assert!(Secret::Command(String::from("exit 1")).resolve().is_err());
+
+        // Settings refer to secrets as e.g. `{"command": "pass show mega"}`.
+        let parsed: CredentialSource = serde_json::from_str(
+            r#"{"encrypted_file": "credentials.enc", "passphrase": {"env": "BACKUPRS_PASSPHRASE"}}"#
+        ).unwrap();
+        assert!(matches!(parsed, CredentialSource::Encrypted { passphrase: Secret::Env(_), .. }));
+
+        let encrypted = EncryptedCredentials::encrypt(&expected, "correct horse", 1000).unwrap();
+        assert!(!encrypted.ciphertext.contains("hunter2"));
+        assert_eq!(encrypted.decrypt("correct horse").unwrap(), expected);
+        assert!(encrypted.decrypt("wrong horse").is_err());
+
+        // The file is only readable by the user, and never overwritten.
+        let file = std::env::temp_dir().join("backuprs-testcredentials.enc");
+        let _ = std::fs::remove_file(&file);
+        encrypted.write_to_file(&file).unwrap();
+        #[cfg(unix)]
+        assert_eq!(std::os::unix::fs::PermissionsExt::mode(&std::fs::metadata(&file).unwrap().permissions()) & 0o777, 0o600);
+        let e = encrypted.write_to_file(&file).unwrap_err();
+        assert_eq!(error::error_code(e.as_ref()), "file_exists");
+        assert_eq!(EncryptedCredentials::read_from_file(&file).unwrap().decrypt("correct horse").unwrap(), expected);
+        std::fs::remove_file(&file).unwrap();
+
+        std::fs::remove_file("testsecret.txt").unwrap();
+    }
+
+    #[test]
+    fn totp_codes() {
+        // Test vectors of RFC 6238, appendix B.
+        let key = b"12345678901234567890";
+        assert_eq!(totp::totp(key, 59, 8), "94287082");
+        assert_eq!(totp::totp(key, 1111111109, 8), "07081804");
+        assert_eq!(totp::totp(key, 2000000000, 8), "69279037");
+        assert_eq!(totp::totp(key, 59, 6), "287082");
+
+        // The same key as shown by authenticator setups.
+        assert_eq!(totp::decode_base32("GEZD GNBV GY3T QOJQ GEZD GNBV GY3T QOJQ").unwrap(), key);
+        assert_eq!(totp::decode_base32("gezdgnbvgy3tqojq======").unwrap(), b"1234567890");
+        assert!(totp::decode_base32("GEZD1").is_none());
+        assert_eq!(totp::current_code("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap().len(), 6);
+        assert!(totp::current_code("not base32!").is_err());
+
+        let mfa: credentials::MfaSource = serde_json::from_str(r#"{"totp": {"value": "GEZDGNBVGY3TQOJQ"}}"#).unwrap();
+        assert_eq!(mfa.code().unwrap().len(), 6);
+        assert_eq!(serde_json::from_str::<credentials::MfaSource>(r#""prompt""#).unwrap(), credentials::MfaSource::Prompt);
+    }
+
+    #[test]
+    fn parse_settings() {
+        use config::{ConfigFormat, parse_settings};
+
+        let toml = r#"
+dirs_to_backup = ["src"]
+dirs_to_ignore = [".git"]
+naming = "{hostname}-{timestamp}.{format}"
+
+[credentials]
+email = { value = "me@example.com" }
+password = { command = "pass show mega" }
+
+[retention]
+keep_daily = 7
+max_quota_percent = 90.0
+"#;
+        let yaml = r#"
+dirs_to_backup: [src]
+dirs_to_ignore: [.git]
+naming: "{hostname}-{timestamp}.{format}"
+credentials:
+  email: { value: me@example.com }
+  password: { command: pass show mega }
+retention:
+  keep_daily: 7
+  max_quota_percent: 90.0
+"#;
+        let from_toml = parse_settings(toml, ConfigFormat::Toml, "settings.toml").unwrap();
+        let from_yaml = parse_settings(yaml, ConfigFormat::Yaml, "settings.yaml").unwrap();
+        assert_eq!(format!("{:?}", from_toml), format!("{:?}", from_yaml));
+        assert_eq!(from_toml.retention.keep_daily, Some(7));
+        assert_eq!(from_toml.on_collision, naming::CollisionPolicy::NewSequence);
+        assert!(from_toml.problems(true).is_empty());
+
+        // Mistakes are reported where they are.
+        let typo = toml.replace("keep_daily", "keep_dayly");
+        let e = parse_settings(&typo, ConfigFormat::Toml, "settings.toml").err().unwrap();
+        assert_eq!((e.line, e.column), (Some(11), Some(1)));
+        assert!(e.message.contains("unknown field `keep_dayly`"), "{}", e.message);
+
+        let e = parse_settings(&yaml.replace("keep_daily: 7", "keep_daily: seven"), ConfigFormat::Yaml, "settings.yaml").err().unwrap();
+        assert_eq!(e.line, Some(9));
+        assert!(e.to_string().starts_with("settings.yaml:9:"), "{}", e);
+
+        let e = parse_settings("{\n  \"dirs_to_backup\": \"src\"\n}", ConfigFormat::Json, "settings.json").err().unwrap();
+        assert_eq!(e.line, Some(2));
+
+        let e = parse_settings(&toml.replace("{format}", "{fromat}"), ConfigFormat::Toml, "settings.toml").err().unwrap();
+        assert_eq!(e.line, Some(4));
+
+        // Everything else is validated at once.
+        let invalid = toml
+            .replace(r#"["src"]"#, r#"["src", "missing/src", "not_there"]"#)
+            .replace(r#"[".git"]"#, r#"["*.tmp"]"#)
+            .replace("90.0", "190.0");
+        let problems = parse_settings(&invalid, ConfigFormat::Toml, "settings.toml").unwrap().problems(true);
+        assert_eq!(problems.len(), 4, "{:?}", problems);
+        assert!(problems[0].starts_with("`dirs_to_backup[1]`"));
+        assert!(problems[1].starts_with("`dirs_to_backup[2]`"));
+        assert!(problems[2].starts_with("`dirs_to_ignore[0]`"));
+        assert!(problems[3].starts_with("`retention`"));
+
+        assert_eq!(ConfigFormat::from_path(std::path::Path::new("settings.YML")), ConfigFormat::Yaml);
+    }
+
+    #[test]
+    fn expand_paths() {
+        use config::{parse_settings, ConfigFormat};
+
+        // Variables are read from an environment of the test's own, see `settings_discovery`.
+        let home = if cfg!(target_os = "windows") { "C:\\Users\\me" } else { "/home/me" };
+        let vars: std::collections::HashMap<&str, std::ffi::OsString> = [
+            ("HOME", home), ("USERPROFILE", home), ("PROJECT", "notes"),
+        ].into_iter().map(|(name, value)| (name, value.into())).collect();
+        let env = |name: &str| vars.get(name).cloned();
+        let expand = |value: &str| paths::expand_in(value, &env);
+        let hostname = utils::hostname();
+
+        assert_eq!(expand("~/src").unwrap(), format!("{}/src", home));
+        assert_eq!(expand("~").unwrap(), home);
+        assert_eq!(expand("a/~/b").unwrap(), "a/~/b");
+        assert_eq!(expand("/data/$PROJECT/x").unwrap(), "/data/notes/x");
+        assert_eq!(expand("/data/${PROJECT}_old").unwrap(), "/data/notes_old");
+        assert_eq!(expand("/Root/{hostname}").unwrap(), format!("/Root/{}", hostname));
+        assert_eq!(expand("/cost/$$5/{other}/$").unwrap(), "/cost/$5/{other}/$");
+        assert!(expand("/data/${UNSET}").unwrap_err().contains("`UNSET`"));
+        assert!(expand("/data/${PROJECT").is_err());
+
+        let toml = r#"
+dirs_to_ignore = ["$PROJECT"]
+backup_folder = "/Root/Backups/{hostname}"
+credentials = { encrypted_file = "~/credentials.enc", passphrase = { file = "${UNSET}/pass" } }
+
+[jobs.docs]
+dirs_to_backup = ["~/Documents"]
+"#;
+        let mut settings = parse_settings(toml, ConfigFormat::Toml, "settings.toml").unwrap();
+        let problems = settings.expand_paths_in(&env);
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].starts_with("`credentials.passphrase.file`"));
+
+        let job = settings.job(Some("docs")).unwrap();
+        assert_eq!(job.dirs_to_backup, vec![format!("{}/Documents", home)]);
+        assert_eq!(job.dirs_to_ignore, vec![String::from("notes")]);
+        assert_eq!(job.backup_folder, format!("/Root/Backups/{}", hostname));
+        match settings.credentials {
+            Some(credentials::CredentialSource::Encrypted { encrypted_file, .. }) => {
+                assert_eq!(encrypted_file, Path::new(&home).join("credentials.enc"));
+            }
+            _ => panic!("Expected encrypted credentials."),
+        }
+    }
+
+    #[test]
+    fn override_settings() {
+        use config::{apply_overrides, parse_settings, ConfigFormat, Override};
+
+        let toml = r#"
+dirs_to_backup = ["src"]
+credentials = { email = { value = "me@example.com" }, password = { value = "hunter2" } }
+
+[retention]
+keep_daily = 7
+"#;
+        let settings = parse_settings(toml, ConfigFormat::Toml, "settings.toml").unwrap();
+
+        let env = |name: &str, value: &str| Override::from_env(name, value).unwrap();
+        let flag = |flag: &str| Override::from_flag(flag).unwrap();
+        assert!(Override::from_env(config::CONFIG_ENV_VAR, "settings.toml").is_none());
+        assert!(Override::from_env("HOME", "/home/me").is_none());
+        assert_eq!(env("BACKUPRS_RETENTION__KEEP_DAILY", "3").path, "retention.keep_daily");
+        assert!(Override::from_flag("retention.keep_daily").is_none());
+
+        // Flags come after the environment, so they take precedence.
+        let overrides = [
+            env("BACKUPRS_RETENTION__KEEP_DAILY", "3"),
+            env("BACKUPRS_DIRS_TO_IGNORE", r#"[".git", "target"]"#),
+            env("BACKUPRS_NAMING", "{timestamp}-1.{format}"),
+            env("BACKUPRS_JOBS__DOCS__DIRS_TO_BACKUP", r#"["src"]"#),
+            env("BACKUPRS_BACKUP_FOLDER", "/Root/Elsewhere"),
+            flag("retention.keep_daily=14"),
+            flag("mfa=prompt"),
+            flag("jobs.docs.max_file_mb=7"),
+        ];
+        let overridden = apply_overrides(settings, &overrides).unwrap();
+        assert_eq!(overridden.retention.keep_daily, Some(14));
+        assert_eq!(overridden.dirs_to_ignore, vec![String::from(".git"), String::from("target")]);
+        assert_eq!(String::from(overridden.naming.clone()), "{timestamp}-1.{format}");
+        assert_eq!(overridden.mfa, Some(credentials::MfaSource::Prompt));
+        assert_eq!(overridden.backup_folder, "/Root/Elsewhere");
+        let job = overridden.job(Some("docs")).unwrap();
+        assert_eq!((job.max_file_mb, job.backup_folder.as_str()), (7, "/Root/Elsewhere"));
+
+        // Unknown settings and values of the wrong type are reported by where they come from.
+        let settings = parse_settings(toml, ConfigFormat::Toml, "settings.toml").unwrap();
+        let e = apply_overrides(settings.clone(), &[env("BACKUPRS_RETENTION__KEEP_DAYLY", "3")]).unwrap_err();
+        assert_eq!(e.file, "BACKUPRS_RETENTION__KEEP_DAYLY");
+        assert!(e.message.contains("keep_dayly"), "{}", e.message);
+        let e = apply_overrides(settings.clone(), &[flag("retention.keep_dayly=3")]).unwrap_err();
+        assert_eq!(e.file, "--set retention.keep_dayly");
+        let e = apply_overrides(settings.clone(), &[flag("retentions.keep_daily=3")]).unwrap_err();
+        assert_eq!(e.file, "--set retentions.keep_daily");
+        let e = apply_overrides(settings.clone(), &[flag("retention.keep_daily=often")]).unwrap_err();
+        assert_eq!(e.file, "--set retention.keep_daily");
+        assert!(apply_overrides(settings.clone(), &[flag("retention..keep_daily=3")]).is_err());
+
+        // Variables of other tools sharing the prefix are ignored, and don't stand in
+        // for a settings file.
+        assert!(!env("BACKUPRS_TOKEN", "secret").is_setting());
+        assert!(env("BACKUPRS_BACKUP_FOLDER", "/Root/Elsewhere").is_setting());
+        assert!(flag("retentions.keep_daily=3").is_setting());
+        let ignored = apply_overrides(settings.clone(), &[env("BACKUPRS_TOKEN", "secret")]).unwrap();
+        assert_eq!(ignored.retention, settings.retention);
+
+        // Jobs are matched ignoring case, as environment variables are upper case.
+        let toml_jobs = "[jobs.Photos]\ndirs_to_backup = [\"src\"]\n";
+        let jobs = parse_settings(toml_jobs, ConfigFormat::Toml, "settings.toml").unwrap();
+        let jobs = apply_overrides(jobs, &[env("BACKUPRS_JOBS__PHOTOS__MAX_FILE_MB", "7")]).unwrap();
+        assert_eq!(jobs.job(Some("Photos")).unwrap().max_file_mb, 7);
+        assert_eq!(jobs.jobs().len(), 1);
+
+        // Secrets written in the settings aren't shown.
+        let shown = toml::to_string_pretty(&overridden.redacted()).unwrap();
+        assert!(!shown.contains("hunter2"), "{}", shown);
+        assert!(shown.contains("<redacted>") && shown.contains("me@example.com"), "{}", shown);
+        let reparsed = parse_settings(&shown, ConfigFormat::Toml, "shown.toml").unwrap();
+        assert_eq!(reparsed.retention, overridden.retention);
+        assert_eq!(reparsed.jobs().len(), 1);
+    }
+
+    #[test]
+    fn settings_discovery() {
+        let base = std::env::temp_dir().join("backuprs-testdiscovery");
+        let _ = std::fs::remove_dir_all(&base);
+
+        // The folders are found in an environment of the test's own, since other tests
+        // read the process's environment while running in parallel.
+        let vars: std::collections::HashMap<&str, std::ffi::OsString> = [
+            ("HOME", "/home/me"),
+            ("USERPROFILE", "C:\\Users\\me"),
+            ("XDG_CONFIG_HOME", base.to_str().unwrap()),
+            ("APPDATA", base.to_str().unwrap()),
+            ("XDG_CACHE_HOME", "relative/cache"),
+            ("LOCALAPPDATA", "relative/cache"),
+        ].into_iter().map(|(name, value)| (name, value.into())).collect();
+        let env = |name: &str| vars.get(name).cloned();
+
+        let config_dir = base.join("backuprs");
+        assert_eq!(paths::config_dir_in(&env), Some(config_dir.clone()));
+        // Relative paths are ignored, as the XDG specification requires.
+        let cache_dir = if cfg!(target_os = "windows") {
+            std::env::temp_dir().join("backuprs")
+        } else {
+            std::path::PathBuf::from("/home/me/.cache/backuprs")
+        };
+        assert_eq!(paths::cache_dir_in(&env), cache_dir);
+
+        // Settings in the working directory come first.
+        let working_dir = base.join("work");
+        let dirs = vec![working_dir.clone(), config_dir.clone()];
+        std::fs::create_dir_all(&working_dir).unwrap();
+        std::fs::create_dir_all(&config_dir).unwrap();
+        std::fs::write(config_dir.join("settings.yaml"), "dirs_to_backup: [src]").unwrap();
+        assert_eq!(config::find_settings_file_in(None, &dirs, false).unwrap(), Some(config_dir.join("settings.yaml")));
+        std::fs::write(working_dir.join("settings.json"), "{}").unwrap();
+        assert_eq!(config::find_settings_file_in(None, &dirs, false).unwrap(), Some(working_dir.join("settings.json")));
+
+        // A file given explicitly must exist.
+        let e = config::find_settings_file_in(Some(base.join("missing.toml")), &dirs, false).unwrap_err();
+        assert_eq!(e.searched, vec![base.join("missing.toml")]);
+
+        // Without any file, the settings may still come from overrides.
+        std::fs::remove_dir_all(&base).unwrap();
+        assert_eq!(config::find_settings_file_in(None, &dirs, true).unwrap(), None);
+        let e = config::find_settings_file_in(None, &dirs, false).unwrap_err();
+        assert_eq!(e.searched.len(), 2 * config::SETTINGS_FILES.len());
+    }
+
+    #[test]
+    fn named_jobs() {
+        use config::{parse_settings, ConfigFormat};
+
+        let toml = r#"
+dirs_to_ignore = [".git"]
+credentials = { email = { value = "me@example.com" }, password = { env = "MEGA_PASSWORD" } }
+
+[retention]
+keep_last = 3
+
+[jobs.documents]
+dirs_to_backup = ["src"]
+
+[jobs.photos]
+dirs_to_backup = ["src"]
+dirs_to_ignore = []
+max_file_mb = 2048
+backup_folder = "/Root/Photos"
+retention = { keep_monthly = 12 }
+"#;
+        let settings = parse_settings(toml, ConfigFormat::Toml, "settings.toml").unwrap();
+        assert!(settings.problems(false).is_empty(), "{:?}", settings.problems(false));
+
+        // Jobs take whatever they don't set from the top level.
+        let jobs = settings.jobs();
+        assert_eq!(jobs.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(), vec!["documents", "photos"]);
+        assert_eq!(jobs[0].dirs_to_ignore, vec![String::from(".git")]);
+        assert_eq!(jobs[0].max_file_mb, config::DEFAULT_MAX_FILE_MB);
+        assert_eq!(jobs[0].backup_folder, config::DEFAULT_BACKUP_FOLDER);
+        assert_eq!(jobs[0].retention.keep_last, Some(3));
+        assert!(jobs[1].dirs_to_ignore.is_empty());
+        assert_eq!((jobs[1].max_file_mb, jobs[1].backup_folder.as_str()), (2048, "/Root/Photos"));
+        assert_eq!((jobs[1].retention.keep_last, jobs[1].retention.keep_monthly), (None, Some(12)));
+
+        let selected = settings.select_jobs(&[String::from("photos")]).unwrap();
+        assert_eq!(selected, vec![jobs[1].clone()]);
+        assert_eq!(settings.select_jobs(&[]).unwrap(), jobs);
+        assert!(settings.select_jobs(&[String::from("music")]).unwrap_err().to_string().contains("documents, photos"));
+        assert_eq!(settings.job(Some("documents")).unwrap(), jobs[0]);
+        assert!(settings.job(None).unwrap_err().job.is_none());
+
+        // Without jobs, the top level is the only job.
+        let single = parse_settings(r#"{ "email": "bWU=", "password": "cGFzcw==", "dirs_to_backup": ["src"] }"#, ConfigFormat::Json, "settings.json").unwrap();
+        let job = single.job(None).unwrap();
+        assert_eq!((job.name.as_str(), job.dirs_to_backup.len()), (naming::DEFAULT_PROFILE, 1));
+        assert_eq!(job.retention, RetentionPolicy::legacy());
+
+        // Problems point at the job they are in.
+        let invalid = format!("dirs_to_backup = [\"src\"]{}", toml)
+            .replace("[\"src\"]\ndirs_to_ignore = []", "[\"src\", \"missing/target\"]\ndirs_to_ignore = []")
+            .replace("/Root/Photos", "Photos")
+            .replace("keep_monthly = 12", "keep_within = \"soon\"");
+        let problems = parse_settings(&invalid, ConfigFormat::Toml, "settings.toml").unwrap().problems(true);
+        assert_eq!(problems.len(), 4, "{:?}", problems);
+        assert!(problems[0].starts_with("`dirs_to_backup` can't be set together with `jobs`"));
+        assert!(problems[1].starts_with("`jobs.photos.dirs_to_backup[1]`"));
+        assert!(problems[2].starts_with("`jobs.photos.backup_folder`"));
+        assert!(problems[3].starts_with("`jobs.photos.retention`"));
+
+        // Jobs sharing a folder need `{profile}` in the names of their backups.
+        let shared = toml.replace("backup_folder = \"/Root/Photos\"", "");
+        assert!(parse_settings(&shared, ConfigFormat::Toml, "settings.toml").unwrap().problems(false).is_empty());
+        let shared = format!("naming = \"backup-{{timestamp}}.{{format}}\"\n{}", shared);
+        let problems = parse_settings(&shared, ConfigFormat::Toml, "settings.toml").unwrap().problems(false);
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].contains("`jobs.documents` and `jobs.photos`"));
+    }
+
+    #[test]
+    fn json_reports() {
+        let e: Box<dyn std::error::Error> = error::BackupNotFoundError{ query: String::from("latest") }.into();
+        assert_eq!(error::error_code(e.as_ref()), "backup_not_found");
+        let e: Box<dyn std::error::Error> = error::BackupFolderNotFoundError{ folder: String::from("/Root/Backups") }.into();
+        assert_eq!(error::error_code(e.as_ref()), "backup_folder_not_found");
+        let e: Box<dyn std::error::Error> = std::io::Error::other("disk full").into();
+        assert_eq!(error::error_code(e.as_ref()), "io");
+        let e: Box<dyn std::error::Error> = error::CorruptFilesRestoredError{ archive: String::from("backup.tar.gz"), corrupt: 1 }.into();
+        assert_eq!(error::error_code(e.as_ref()), "corrupt_files_restored");
+
+        // Only settings errors list their problems.
+        let e = error::InvalidConfigError{ file: String::from("settings.toml"), problems: vec![String::from("`dirs_to_backup` is empty.")] };
+        let report = serde_json::to_value(error::ErrorReport::new(&e)).unwrap();
+        assert_eq!(report["code"], "invalid_config");
+        assert_eq!(report["problems"], serde_json::json!(["`dirs_to_backup` is empty."]));
+        let report = serde_json::to_value(error::ErrorReport::new(&error::UsageError{ reason: String::from("no") })).unwrap();
+        assert_eq!(report, serde_json::json!({ "code": "usage", "message": "no" }));
+
+        let e = error::CorruptBackupError{ file_name: String::from("backup.tar.gz"), reason: String::from("truncated") };
+        let summary = summary::JobSummary { error: Some(error::ErrorReport::new(&e)), ..summary::JobSummary::new("photos") };
+        assert!(!summary.is_ok());
+        let summary = serde_json::to_value(&summary).unwrap();
+        assert_eq!((&summary["job"], &summary["archive"]), (&serde_json::json!("photos"), &serde_json::Value::Null));
+        assert_eq!(summary["error"]["code"], "corrupt_backup");
+    }
+
+    #[test]
+    fn browse_manifest() {
+        let entry = |path: &str, size: u64| ManifestEntry { path: String::from(path), size, modified: None, offset: None, sha256: None };
+        let manifest = Manifest {
+            archive: String::from("backup2024-01-01.tar.gz"),
+            entries: vec![
+                entry("notes/todo.txt", 10),
+                entry("notes/ideas/rust.md", 20),
+                entry("notes/ideas/go.md", 5),
+                entry("projects/Makefile", 1),
+            ],
+        };
+
+        let names: Vec<_> = manifest.list_dir("").into_iter().map(|x| (x.name, x.is_dir, x.size)).collect();
+        assert_eq!(names, vec![(String::from("notes"), true, 35), (String::from("projects"), true, 1)]);
+
+        let names: Vec<_> = manifest.list_dir("notes\\").into_iter().map(|x| (x.name, x.is_dir, x.size)).collect();
+        assert_eq!(names, vec![(String::from("ideas"), true, 25), (String::from("todo.txt"), false, 10)]);
+
+        let found = manifest.find(&restore::PathFilter::parse("*.md").unwrap());
+        assert_eq!(found.len(), 2);
+
+        let file_name = "testmanifest.index.json.gz";
+        manifest.write_to_file(file_name).unwrap();
+        let read = Manifest::read_from(std::fs::File::open(file_name).unwrap()).unwrap();
+        assert_eq!(read, manifest);
+        std::fs::remove_file(file_name).unwrap();
+
+        assert_eq!(manifest::index_name("backup2024-01-01.tar.gz"), "backup2024-01-01.index.json.gz");
+    }
+
+    #[test]
+    fn diff_manifests() {
+        let entry = |path: &str, size: u64| ManifestEntry { path: String::from(path), size, modified: None, offset: None, sha256: None };
+        let old = Manifest {
+            archive: String::from("backup2024-01-01.tar.gz"),
+            entries: vec![entry("notes/todo.txt", 10), entry("notes/old.txt", 4), entry("Makefile", 1)],
+        };
+        let new = Manifest {
+            archive: String::from("backup2024-01-02.tar.gz"),
+            entries: vec![entry("notes/todo.txt", 15), entry("notes/new.txt", 7), entry("Makefile", 1)],
+        };
+
+        let changes: Vec<_> = manifest::diff(&old, &new).into_iter().map(|x| (x.path, x.kind, x.size_delta)).collect();
+        assert_eq!(changes, vec![
+            (String::from("notes/new.txt"), manifest::ChangeKind::Added, 7),
+            (String::from("notes/old.txt"), manifest::ChangeKind::Removed, -4),
+            (String::from("notes/todo.txt"), manifest::ChangeKind::Modified, 5),
+        ]);
+
+        // Checksums are compared when both manifests have them, whatever the size and date.
+        let with_sha256 = |manifest: &Manifest, sha256: &str| Manifest {
+            archive: manifest.archive.clone(),
+            entries: vec![ManifestEntry { sha256: Some(String::from(sha256)), ..entry("Makefile", 1) }],
+        };
+        assert_eq!(manifest::diff(&with_sha256(&old, "aa"), &with_sha256(&new, "bb")).len(), 1);
+        assert!(manifest::diff(&with_sha256(&old, "aa"), &with_sha256(&new, "aa")).is_empty());
+
+        // Reading the headers of an archive gives the same entries as its index.
+        let file_name = "testdiff.tar.gz";
+        let manifest = create_tarball_from_dirs(vec![String::from("src")], file_name, 512, None).unwrap();
+        let from_headers = Manifest::from_archive(std::fs::File::open(file_name).unwrap(), file_name).unwrap();
+        std::fs::remove_file(file_name).unwrap();
+        assert!(manifest::diff(&manifest, &from_headers).is_empty());
+
+        let offsets = |manifest: &Manifest| -> Vec<_> { manifest.entries.iter().map(|x| x.offset).collect() };
+        assert_eq!(offsets(&manifest), offsets(&from_headers));
+    }
+
     #[tokio::test]
     async fn authentication() {
         let SettingsEnv { 
             email: email_decoded, password: pass_decoded , ..
-        } = utils::read_auth_info(SETTINGS_FILE).unwrap();
+        } = config::read_auth_info(config::find_settings_file().unwrap().as_deref()).unwrap();
 
         let mut client = BackupClient::default();
         client.login(&email_decoded, &pass_decoded, None).await
@@ -449,7 +2549,7 @@ mod tests {
     async fn upload_remove_file() {
         let SettingsEnv { 
             email: email_decoded, password: pass_decoded , ..
-        } = utils::read_auth_info(SETTINGS_FILE).unwrap();
+        } = config::read_auth_info(config::find_settings_file().unwrap().as_deref()).unwrap();
 
         let mut client = BackupClient::new(String::from("/Root/Backups"));
         client.login(&email_decoded, &pass_decoded, None).await
@@ -1,11 +1,8 @@
-// Disabling the terminal window on Windows.
-#![cfg_attr(
-	target_os = "windows",
-	windows_subsystem = "windows"
-)]
+use std::path::PathBuf;
 
+use backuprs::{CheckSelection, ConflictPolicy, OutputFormat, RestoreOptions};
 use chrono::Local;
-mod error;
+use clap::{Parser, Subcommand};
 
 /// Current version of backup.rs, read from Cargo.toml.
 /// It is an `Option<&str>`, because if the lib is not compiled
@@ -13,7 +10,185 @@ mod error;
 const VERSION: Option<&str> = option_env!("CARGO_PKG_VERSION");
 const PKG_NAME: Option<&str> = option_env!("CARGO_PKG_NAME");
 
-fn setup_logger() -> Result<(), fern::InitError> {
+#[derive(Parser)]
+#[command(name = "backuprs", version, about)]
+struct Cli {
+    /// Settings file to read, instead of `BACKUPRS_CONFIG`, `./settings.*` or
+    /// `~/.config/backuprs/settings.*`.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Override a setting, e.g. `--set retention.keep_daily=7`. Takes precedence over
+    /// `BACKUPRS_*` environment variables, which take precedence over the settings file.
+    #[arg(long = "set", global = true, value_name = "PATH=VALUE")]
+    set: Vec<String>,
+    /// Job to use, as named in the settings. `backup` takes it several times,
+    /// and runs every job without it.
+    #[arg(long = "job", global = true, value_name = "JOB")]
+    jobs: Vec<String>,
+    /// Output format. `json` prints a single JSON document, errors included.
+    #[arg(long, value_enum, global = true, default_value_t = OutputFormat::Table)]
+    format: OutputFormat,
+    /// Log more, `-vv` for everything.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+    /// Log only warnings and errors, `-qq` for errors only.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    quiet: u8,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Archive the configured folders and upload them to MEGA (default).
+    Backup {
+        /// Only list the files that would be backed up and estimate the archive's
+        /// size, without creating it or contacting MEGA.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Download a backup from MEGA and extract it.
+    Restore {
+        /// Name of the backup, its date (YYYY-MM-DD) or `latest`.
+        snapshot: String,
+        /// Restore into this directory instead of the original locations.
+        #[arg(long)]
+        target: Option<PathBuf>,
+        /// What to do with files that already exist.
+        #[arg(long, value_enum, default_value_t = ConflictPolicy::Overwrite)]
+        conflict: ConflictPolicy,
+        /// Restore only these paths (e.g. `backup_folder/notes`) or glob patterns
+        /// (e.g. `backup_folder/*.txt`). The archive is streamed instead of downloaded.
+        paths: Vec<String>,
+    },
+    /// List the backups stored in MEGA.
+    List {
+        /// List only the backups pinned with this tag.
+        #[arg(long)]
+        tag: Option<String>,
+        /// List only the pinned backups.
+        #[arg(long)]
+        pinned: bool,
+    },
+    /// Protect a backup from pruning.
+    Pin {
+        /// Name of the backup, its date (YYYY-MM-DD) or `latest`.
+        snapshot: String,
+        /// Label to list the backup by, e.g. `before-migration`.
+        #[arg(long)]
+        tag: Option<String>,
+        /// Why the backup is pinned.
+        #[arg(long)]
+        note: Option<String>,
+    },
+    /// Let a pinned backup be pruned again.
+    Unpin {
+        /// Name of the backup, its date (YYYY-MM-DD) or `latest`.
+        snapshot: String,
+    },
+    /// Check the settings file, or show the settings in effect.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Manage the MEGA credentials.
+    Credentials {
+        #[command(subcommand)]
+        command: CredentialsCommand,
+    },
+    /// Delete the backups not kept by the retention policy.
+    Prune {
+        /// Only print the backups that would be deleted.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// List a directory inside a backup, without downloading the backup.
+    Ls {
+        /// Name of the backup, its date (YYYY-MM-DD) or `latest`.
+        snapshot: String,
+        /// Directory inside the backup, e.g. `backup_folder/notes`.
+        #[arg(default_value = "")]
+        dir: String,
+    },
+    /// Find files inside a backup, without downloading the backup.
+    Find {
+        /// Name of the backup, its date (YYYY-MM-DD) or `latest`.
+        snapshot: String,
+        /// Path (e.g. `backup_folder/notes`) or glob pattern (e.g. `*.txt`).
+        pattern: String,
+    },
+    /// List the files added, removed or modified between two backups.
+    Diff {
+        /// Name of the older backup, its date (YYYY-MM-DD) or `latest`.
+        old: String,
+        /// Name of the newer backup, its date (YYYY-MM-DD) or `latest`.
+        new: String,
+    },
+    /// Download backups and verify the checksums of every file in them.
+    Check {
+        /// Name of the backup to check, its date (YYYY-MM-DD) or `latest`.
+        #[arg(default_value = "latest", conflicts_with_all = ["all", "sample"])]
+        snapshot: String,
+        /// Check every backup.
+        #[arg(long, conflicts_with = "sample")]
+        all: bool,
+        /// Check this many randomly chosen backups.
+        #[arg(long)]
+        sample: Option<usize>,
+    },
+    /// Mount backups as a read-only filesystem.
+    #[cfg(all(target_os = "linux", feature = "fuse"))]
+    Mount {
+        /// Existing, empty directory to mount the backups at.
+        mountpoint: PathBuf,
+        /// Mount only this backup (name, date or `latest`) instead of every backup.
+        #[arg(long, conflicts_with = "archive")]
+        snapshot: Option<String>,
+        /// Mount a local archive instead of the backups stored in MEGA.
+        #[arg(long)]
+        archive: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Report every problem in the settings file, including missing folders.
+    Validate,
+    /// Print the settings in effect, with the overrides applied and secrets redacted.
+    /// With `--format table`, the settings are printed as TOML.
+    Show,
+}
+
+#[derive(Subcommand)]
+enum CredentialsCommand {
+    /// Ask for the credentials and save them encrypted with a passphrase.
+    Encrypt {
+        /// Where to save the encrypted credentials.
+        #[arg(default_value = "credentials.enc")]
+        output: PathBuf,
+    },
+}
+
+/// Level of the log shown on the console, from the number of `-v` and `-q` flags.
+fn console_level(verbose: u8, quiet: u8) -> log::LevelFilter {
+    match (verbose, quiet) {
+        (0, 0) => log::LevelFilter::Info,
+        (0, 1) => log::LevelFilter::Warn,
+        (0, _) => log::LevelFilter::Error,
+        (1, _) => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    }
+}
+
+fn setup_logger(console_level: log::LevelFilter) -> Result<(), fern::InitError> {
+    // The log goes to the state folder (e.g. `~/.local/state/backuprs`), since the
+    // working directory of scheduled runs is often not writable or not the same.
+    let log_dir = backuprs::state_dir();
+    std::fs::create_dir_all(&log_dir)?;
+    // The file keeps the details needed to look into a failed run, however quiet
+    // the console is.
+    let file_level = console_level.max(log::LevelFilter::Debug);
+
     fern::Dispatch::new()
         .format(|out, message, record| {
             out.finish(format_args!(
@@ -26,22 +201,94 @@ fn setup_logger() -> Result<(), fern::InitError> {
                 message
             ))
         })
-        .level(log::LevelFilter::Debug)
-        .chain(std::io::stdout())
-        .chain(fern::log_file("output.log")?)
+        .level(file_level)
+        // Logging to stderr, so that stdout only holds what the commands print.
+        .chain(fern::Dispatch::new().level(console_level).chain(std::io::stderr()))
+        .chain(fern::log_file(log_dir.join("output.log"))?)
         .apply()?;
     Ok(())
 }
 
+/// Logs an error, also prints it as a JSON document if `format` is `json`, and exits.
+fn exit_with_error(e: &(dyn std::error::Error + 'static), format: OutputFormat, code: i32) -> ! {
+    log::error!("{}", e);
+    if format == OutputFormat::Json {
+        let _ = backuprs::print_error(e);
+    }
+    std::process::exit(code);
+}
+
 fn main() {
-    setup_logger().unwrap();
-    match backuprs::run() {
+    let cli = Cli::parse();
+    setup_logger(console_level(cli.verbose, cli.quiet)).unwrap();
+    if let Some(config) = cli.config {
+        backuprs::use_settings_file(config);
+    }
+    let format = cli.format;
+    if let Err(e) = backuprs::use_setting_flags(&cli.set) {
+        exit_with_error(&e, format, 2);
+    }
+
+    let command = cli.command.unwrap_or(Command::Backup { dry_run: false });
+    if cli.jobs.len() > 1 && !matches!(command, Command::Backup { .. }) {
+        let e = backuprs::error::UsageError {
+            reason: String::from("Only `backup` can be run for several jobs at once."),
+        };
+        exit_with_error(&e, format, 2);
+    }
+    let job = cli.jobs.first().map(String::as_str);
+
+    let result = match command {
+        Command::Backup { dry_run: true } => backuprs::backup_dry_run(&cli.jobs, format),
+        Command::Backup { dry_run: false } => backuprs::run(&cli.jobs, format),
+        Command::Restore { snapshot, target, conflict, paths } => {
+            backuprs::restore(job, RestoreOptions { snapshot, target, filters: paths, conflict }, format)
+        }
+        Command::List { tag, pinned } => backuprs::list(job, format, tag, pinned),
+        Command::Pin { snapshot, tag, note } => backuprs::pin(job, &snapshot, tag, note, format),
+        Command::Unpin { snapshot } => backuprs::unpin(job, &snapshot, format),
+        Command::Config { command: ConfigCommand::Validate } => backuprs::validate_config(format),
+        Command::Config { command: ConfigCommand::Show } => backuprs::show_config(format),
+        Command::Credentials { command: CredentialsCommand::Encrypt { output } } => {
+            backuprs::encrypt_credentials(&output, format)
+        }
+        Command::Prune { dry_run } => backuprs::prune(job, dry_run, format),
+        Command::Ls { snapshot, dir } => backuprs::ls(job, &snapshot, &dir, format),
+        Command::Find { snapshot, pattern } => backuprs::find(job, &snapshot, &pattern, format),
+        Command::Diff { old, new } => backuprs::diff(job, &old, &new, format),
+        Command::Check { snapshot, all, sample } => {
+            let selection = match (all, sample) {
+                (true, _) => CheckSelection::All,
+                (false, Some(size)) => CheckSelection::Sample(size),
+                (false, None) => CheckSelection::Snapshot(snapshot),
+            };
+            backuprs::check(job, selection, format)
+        }
+        #[cfg(all(target_os = "linux", feature = "fuse"))]
+        Command::Mount { mountpoint, snapshot, archive } => {
+            backuprs::mount(job, snapshot, archive, mountpoint)
+        }
+    };
+
+    match result {
         Ok(()) => (),
+        Err(e) if e.is::<backuprs::error::CheckFailedError>()
+            || e.is::<backuprs::error::JobsFailedError>()
+            || e.is::<backuprs::error::CorruptFilesRestoredError>() => {
+            // A failed check, job or restore is an expected outcome, which is already
+            // in the printed reports, so it is only reported through the exit code.
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
         Err(e) => {
-            // Panic if unknown error has been found, since this
-            // can only happen if there is a bug in the application.
-            log::error!("{:?}", e);
-            panic!("{:?}", e);
+            // Errors with a known cause, e.g. invalid settings or a backup that doesn't
+            // exist, are the user's to fix, so only their message is shown. The details
+            // of unknown errors are logged as well, since they may come from a bug.
+            // Scripts get the error as a document with a stable code.
+            if backuprs::error::error_code(e.as_ref()) == "other" {
+                log::error!("{:?}", e);
+            }
+            exit_with_error(e.as_ref(), format, 1);
         }
     };
 }
@@ -0,0 +1,312 @@
+//! Index of the files stored in a backup.
+//!
+//! Every archive is uploaded together with a small, gzipped JSON index listing its
+//! entries, so the contents of a backup can be browsed without downloading it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+
+use crate::restore::{archive_path_components, PathFilter};
+use crate::snapshot::OutputFormat;
+use crate::utils;
+
+/// A single file stored in an archive.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    /// Path of the file in the archive with `/` separators, e.g. `backup_folder/notes/todo.txt`.
+    pub path: String,
+    /// Size of the file in bytes.
+    pub size: u64,
+    pub modified: Option<DateTime<Utc>>,
+    /// Position of the file's contents in the uncompressed tar stream.
+    #[serde(default)]
+    pub offset: Option<u64>,
+    /// SHA-256 checksum of the file's contents as a lowercase hex string.
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+/// Index of an archive.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Manifest {
+    /// File name of the indexed archive.
+    pub archive: String,
+    pub entries: Vec<ManifestEntry>,
+}
+
+/// A file or directory directly inside a listed directory of a manifest.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ListedNode {
+    pub name: String,
+    pub is_dir: bool,
+    /// Size of the file, or the total size of the files below the directory.
+    pub size: u64,
+    /// Modification date of the file, or of the newest file below the directory.
+    pub modified: Option<DateTime<Utc>>,
+}
+
+/// How a file changed between two backups.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// A file that differs between two backups.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Change {
+    pub path: String,
+    pub kind: ChangeKind,
+    pub old_size: Option<u64>,
+    pub new_size: Option<u64>,
+    /// Change of the file's size in bytes, negative if it shrank.
+    pub size_delta: i64,
+}
+
+/// Path of the manifest stored as the last entry of every archive.
+pub const EMBEDDED_MANIFEST_PATH: &str = ".backuprs-manifest.json";
+
+/// Returns the file name of the index uploaded next to the archive `archive_name`.
+/// E.g.: "backup2024-01-01.tar.gz" ----> "backup2024-01-01.index.json.gz"
+pub fn index_name(archive_name: &str) -> String {
+    format!("{}.index.json.gz", archive_name.trim_end_matches(".tar.gz"))
+}
+
+impl Manifest {
+    pub fn new(archive: &str) -> Self {
+        Manifest { archive: String::from(archive), entries: Vec::new() }
+    }
+
+    /// Saves the manifest as gzipped JSON.
+    pub fn write_to_file(&self, file_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut enc = GzEncoder::new(File::create(file_name)?, Compression::best());
+        serde_json::to_writer(&mut enc, self)?;
+        enc.finish()?.flush()?;
+        Ok(())
+    }
+
+    /// Reads a manifest saved by `write_to_file`.
+    pub fn read_from<R: Read>(reader: R) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(serde_json::from_reader(GzDecoder::new(reader))?)
+    }
+
+    /// Lists the files and directories directly inside `dir`, sorted by name.
+    /// An empty `dir` lists the backed up folders themselves.
+    pub fn list_dir(&self, dir: &str) -> Vec<ListedNode> {
+        let dir = archive_path_components(dir).join("/");
+        let mut nodes: BTreeMap<&str, ListedNode> = BTreeMap::new();
+
+        for entry in self.entries.iter() {
+            let rest = if dir.is_empty() {
+                entry.path.as_str()
+            } else {
+                match entry.path.strip_prefix(&dir).and_then(|x| x.strip_prefix('/')) {
+                    Some(rest) => rest,
+                    None => continue,
+                }
+            };
+
+            let (name, is_dir) = match rest.split_once('/') {
+                Some((name, _)) => (name, true),
+                None => (rest, false),
+            };
+
+            let node = nodes.entry(name).or_insert_with(|| ListedNode {
+                name: String::from(name),
+                is_dir,
+                size: 0,
+                modified: None,
+            });
+            node.size += entry.size;
+            node.modified = node.modified.max(entry.modified);
+        }
+
+        nodes.into_values().collect()
+    }
+
+    /// Builds a manifest by reading the headers of a gzipped tar stream, for archives
+    /// that were uploaded without an index. The stream is read to its end.
+    pub fn from_archive<R: Read>(reader: R, archive: &str) -> io::Result<Self> {
+        let mut tar = tar::Archive::new(GzDecoder::new(reader));
+        let mut manifest = Manifest::new(archive);
+
+        for entry in tar.entries()? {
+            let entry = entry?;
+            let header = entry.header();
+            let path = archive_path_components(&String::from_utf8_lossy(&entry.path_bytes())).join("/");
+            if path == EMBEDDED_MANIFEST_PATH {
+                continue;
+            }
+
+            manifest.entries.push(ManifestEntry {
+                path,
+                size: header.size()?,
+                modified: header.mtime().ok().and_then(|x| DateTime::from_timestamp(x as i64, 0)),
+                offset: Some(entry.raw_file_position()),
+                sha256: None,
+            });
+        }
+
+        io::copy(&mut tar.into_inner(), &mut io::sink())?;
+        Ok(manifest)
+    }
+
+    /// Returns the entries selected by `filter`.
+    pub fn find(&self, filter: &PathFilter) -> Vec<&ManifestEntry> {
+        self.entries.iter().filter(|entry| filter.matches(&entry.path)).collect()
+    }
+}
+
+/// Copies the contents of `entry` out of a gzipped tar stream, using the offset
+/// stored in the manifest instead of parsing the headers before it.
+pub fn extract_entry<R: Read, W: Write>(reader: R, entry: &ManifestEntry, writer: &mut W) -> io::Result<u64> {
+    let Some(offset) = entry.offset else {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("No offset stored for {:?}", entry.path)));
+    };
+
+    let mut stream = GzDecoder::new(reader);
+    io::copy(&mut (&mut stream).take(offset), &mut io::sink())?;
+    io::copy(&mut stream.take(entry.size), writer)
+}
+
+/// Compares two manifests, returning the added, removed and modified files sorted by path.
+///
+/// A file counts as modified if its checksum differs, when both manifests have one.
+/// Otherwise it counts as modified if its size or its modification date (to the
+/// second, as stored in tar headers) differs.
+pub fn diff(old: &Manifest, new: &Manifest) -> Vec<Change> {
+    let old_entries: HashMap<&str, &ManifestEntry> = old.entries.iter().map(|x| (x.path.as_str(), x)).collect();
+    let new_entries: HashMap<&str, &ManifestEntry> = new.entries.iter().map(|x| (x.path.as_str(), x)).collect();
+
+    let mut changes = Vec::new();
+
+    for (path, new_entry) in new_entries.iter() {
+        let kind = match old_entries.get(path) {
+            None => ChangeKind::Added,
+            Some(old_entry) => {
+                let unchanged = match (&old_entry.sha256, &new_entry.sha256) {
+                    (Some(old_sha256), Some(new_sha256)) => old_sha256 == new_sha256,
+                    _ => old_entry.size == new_entry.size
+                        && old_entry.modified.map(|x| x.timestamp()) == new_entry.modified.map(|x| x.timestamp()),
+                };
+                if unchanged {
+                    continue;
+                }
+                ChangeKind::Modified
+            }
+        };
+
+        let old_size = old_entries.get(path).map(|x| x.size);
+        changes.push(Change {
+            path: String::from(*path),
+            kind,
+            old_size,
+            new_size: Some(new_entry.size),
+            size_delta: new_entry.size as i64 - old_size.unwrap_or(0) as i64,
+        });
+    }
+
+    for (path, old_entry) in old_entries.iter() {
+        if !new_entries.contains_key(path) {
+            changes.push(Change {
+                path: String::from(*path),
+                kind: ChangeKind::Removed,
+                old_size: Some(old_entry.size),
+                new_size: None,
+                size_delta: -(old_entry.size as i64),
+            });
+        }
+    }
+
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+    changes
+}
+
+/// Prints the changes between two backups to stdout in the given format.
+pub fn print_changes(changes: &[Change], format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(changes)?),
+        OutputFormat::Table => {
+            for change in changes {
+                let sign = match change.kind {
+                    ChangeKind::Added => "+",
+                    ChangeKind::Removed => "-",
+                    ChangeKind::Modified => "M",
+                };
+                let delta = if change.size_delta < 0 {
+                    format!("-{}", utils::format_size(change.size_delta.unsigned_abs()))
+                } else {
+                    format!("+{}", utils::format_size(change.size_delta as u64))
+                };
+                println!("{}  {:>12}  {}", sign, delta, change.path);
+            }
+
+            let count = |kind| changes.iter().filter(|x| x.kind == kind).count();
+            let total: i64 = changes.iter().map(|x| x.size_delta).sum();
+            println!(
+                "{} added, {} removed, {} modified, {}{} in total.",
+                count(ChangeKind::Added),
+                count(ChangeKind::Removed),
+                count(ChangeKind::Modified),
+                if total < 0 { "-" } else { "+" },
+                utils::format_size(total.unsigned_abs())
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the contents of a directory to stdout in the given format.
+pub fn print_listing(nodes: &[ListedNode], format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(nodes)?),
+        OutputFormat::Table => {
+            for node in nodes {
+                println!(
+                    "{:>10}  {:<19}  {}{}",
+                    utils::format_size(node.size),
+                    format_modified(node.modified),
+                    node.name,
+                    if node.is_dir { "/" } else { "" }
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the entries to stdout in the given format.
+pub fn print_entries(entries: &[&ManifestEntry], format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(entries)?),
+        OutputFormat::Table => {
+            for entry in entries {
+                println!(
+                    "{:>10}  {:<19}  {}",
+                    utils::format_size(entry.size),
+                    format_modified(entry.modified),
+                    entry.path
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn format_modified(modified: Option<DateTime<Utc>>) -> String {
+    modified
+        .map(|x| x.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| String::from("-"))
+}
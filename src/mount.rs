@@ -0,0 +1,300 @@
+//! Read-only FUSE filesystem serving the contents of backups.
+//!
+//! The directory tree is built from the backups' manifests when mounting, so browsing
+//! never touches the archives. The contents of a file are only fetched when it is
+//! first read: remote archives are downloaded into the cache directory, and the file
+//! is copied out of the archive using the offset stored in the manifest.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use fuser::{FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request};
+use log::{debug, error, info};
+use mega::Node;
+
+use crate::manifest::{self, Manifest, ManifestEntry};
+use crate::BackupClient;
+
+/// How long the kernel may cache attributes and lookups. Backups never change.
+const TTL: Duration = Duration::from_secs(3600);
+const ROOT_INO: u64 = 1;
+
+/// Where the archive of a mounted backup can be read from.
+pub enum ArchiveSource {
+    /// An archive on the local disk.
+    Local(PathBuf),
+    /// An archive in MEGA, downloaded on first use.
+    Remote(Box<Node>),
+}
+
+/// A backup to be mounted, together with its manifest.
+pub struct MountedSnapshot {
+    pub source: ArchiveSource,
+    pub manifest: Manifest,
+}
+
+enum InodeKind {
+    Dir { children: BTreeMap<OsString, u64> },
+    File { snapshot: usize, entry: ManifestEntry },
+}
+
+struct Inode {
+    parent: u64,
+    kind: InodeKind,
+    modified: SystemTime,
+}
+
+pub struct SnapshotFs {
+    client: Option<BackupClient>,
+    runtime: tokio::runtime::Handle,
+    snapshots: Vec<MountedSnapshot>,
+    inodes: HashMap<u64, Inode>,
+    /// Local copies of the archives, indexed like `snapshots`.
+    archives: HashMap<usize, PathBuf>,
+    /// Files already copied out of their archive.
+    files: HashMap<u64, PathBuf>,
+    cache_dir: PathBuf,
+}
+
+impl SnapshotFs {
+    /// Builds the directory tree of the given backups.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - Logged in client used to download remote archives. It is only
+    ///   needed if any of the snapshots is an `ArchiveSource::Remote`.
+    /// * `runtime` - Handle of the runtime the client's downloads are run on.
+    /// * `snapshots` - Backups to mount. A single backup is mounted at the root,
+    ///   multiple backups get a folder each, named after their archive.
+    /// * `cache_dir` - Directory where downloaded archives and read files are kept.
+    pub fn new(client: Option<BackupClient>, runtime: tokio::runtime::Handle, snapshots: Vec<MountedSnapshot>, cache_dir: PathBuf) -> Self {
+        let mut fs = SnapshotFs {
+            client,
+            runtime,
+            snapshots: Vec::new(),
+            inodes: HashMap::new(),
+            archives: HashMap::new(),
+            files: HashMap::new(),
+            cache_dir,
+        };
+
+        fs.inodes.insert(ROOT_INO, Inode {
+            parent: ROOT_INO,
+            kind: InodeKind::Dir { children: BTreeMap::new() },
+            modified: SystemTime::UNIX_EPOCH,
+        });
+
+        let single = snapshots.len() == 1;
+        for (i, snapshot) in snapshots.iter().enumerate() {
+            let snapshot_ino = if single {
+                ROOT_INO
+            } else {
+                let name = snapshot.manifest.archive.trim_end_matches(".tar.gz");
+                fs.add_dir(ROOT_INO, OsStr::new(name))
+            };
+
+            for entry in snapshot.manifest.entries.iter() {
+                let components: Vec<&str> = entry.path.split('/').collect();
+                let Some((file_name, dirs)) = components.split_last() else {
+                    continue;
+                };
+
+                let parent = dirs.iter().fold(snapshot_ino, |parent, dir| fs.add_dir(parent, OsStr::new(dir)));
+                let modified = entry.modified.map(SystemTime::from).unwrap_or(SystemTime::UNIX_EPOCH);
+                fs.add_inode(parent, OsStr::new(file_name), InodeKind::File { snapshot: i, entry: entry.clone() }, modified);
+            }
+        }
+
+        fs.snapshots = snapshots;
+        fs
+    }
+
+    /// Returns the directory `name` inside `parent`, creating it if it doesn't exist yet.
+    fn add_dir(&mut self, parent: u64, name: &OsStr) -> u64 {
+        if let Some(Inode { kind: InodeKind::Dir { children }, .. }) = self.inodes.get(&parent) {
+            if let Some(&ino) = children.get(name) {
+                return ino;
+            }
+        }
+        self.add_inode(parent, name, InodeKind::Dir { children: BTreeMap::new() }, SystemTime::UNIX_EPOCH)
+    }
+
+    fn add_inode(&mut self, parent: u64, name: &OsStr, kind: InodeKind, modified: SystemTime) -> u64 {
+        let ino = self.inodes.len() as u64 + 1;
+        self.inodes.insert(ino, Inode { parent, kind, modified });
+
+        if let Some(parent) = self.inodes.get_mut(&parent) {
+            if let InodeKind::Dir { children } = &mut parent.kind {
+                children.insert(name.to_os_string(), ino);
+            }
+            parent.modified = parent.modified.max(modified);
+        }
+
+        ino
+    }
+
+    fn attr(&self, ino: u64, req: &Request<'_>) -> Option<FileAttr> {
+        let inode = self.inodes.get(&ino)?;
+        let (kind, size, perm, nlink) = match &inode.kind {
+            InodeKind::Dir { .. } => (FileType::Directory, 0, 0o555, 2),
+            InodeKind::File { entry, .. } => (FileType::RegularFile, entry.size, 0o444, 1),
+        };
+
+        Some(FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: inode.modified,
+            mtime: inode.modified,
+            ctime: inode.modified,
+            crtime: inode.modified,
+            kind,
+            perm,
+            nlink,
+            uid: req.uid(),
+            gid: req.gid(),
+            rdev: 0,
+            blksize: 512,
+            flags: 0,
+        })
+    }
+
+    /// Returns the local copy of the archive of a snapshot, downloading it if necessary.
+    fn archive(&mut self, snapshot: usize) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        if let Some(path) = self.archives.get(&snapshot) {
+            return Ok(path.clone());
+        }
+
+        let path = match &self.snapshots[snapshot].source {
+            ArchiveSource::Local(path) => path.clone(),
+            ArchiveSource::Remote(node) => {
+                let client = self.client.as_ref().ok_or("Not logged in to download remote archives.")?;
+                let path = self.cache_dir.join(node.name());
+                info!("Downloading {:?} into {:?}...", node.name(), path);
+                self.runtime.block_on(client.download_file(node, &path.to_string_lossy()))?;
+                path
+            }
+        };
+
+        self.archives.insert(snapshot, path.clone());
+        Ok(path)
+    }
+
+    /// Returns the path of a file copied out of its archive, copying it if necessary.
+    fn file(&mut self, ino: u64) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        if let Some(path) = self.files.get(&ino) {
+            return Ok(path.clone());
+        }
+
+        let (snapshot, entry) = match self.inodes.get(&ino).map(|x| &x.kind) {
+            Some(InodeKind::File { snapshot, entry }) => (*snapshot, entry.clone()),
+            _ => return Err("Not a file.".into()),
+        };
+
+        let archive = self.archive(snapshot)?;
+        let path = self.cache_dir.join("files").join(ino.to_string());
+        std::fs::create_dir_all(self.cache_dir.join("files"))?;
+
+        debug!("Copying {:?} out of {:?}...", entry.path, archive);
+        let mut file = File::create(&path)?;
+        manifest::extract_entry(File::open(&archive)?, &entry, &mut file)?;
+
+        self.files.insert(ino, path.clone());
+        Ok(path)
+    }
+}
+
+impl Filesystem for SnapshotFs {
+    fn destroy(&mut self) {
+        if let Some(mut client) = self.client.take() {
+            self.runtime.block_on(client.try_logout());
+        }
+        if let Err(e) = std::fs::remove_dir_all(&self.cache_dir) {
+            error!("Couldn't remove cache directory {:?}: {:?}", self.cache_dir, e);
+        }
+    }
+
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let ino = match self.inodes.get(&parent).map(|x| &x.kind) {
+            Some(InodeKind::Dir { children }) => children.get(name).copied(),
+            _ => None,
+        };
+
+        match ino.and_then(|ino| self.attr(ino, req)) {
+            Some(attr) => reply.entry(&TTL, &attr, 0),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.attr(ino, req) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let read = |path: PathBuf| -> io::Result<Vec<u8>> {
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::Start(offset as u64))?;
+            let mut buffer = Vec::with_capacity(size as usize);
+            file.take(size as u64).read_to_end(&mut buffer)?;
+            Ok(buffer)
+        };
+
+        match self.file(ino) {
+            Ok(path) => match read(path) {
+                Ok(buffer) => reply.data(&buffer),
+                Err(e) => {
+                    error!("Couldn't read inode {:?}: {:?}", ino, e);
+                    reply.error(libc::EIO)
+                }
+            },
+            Err(e) => {
+                error!("Couldn't fetch inode {:?}: {:?}", ino, e);
+                reply.error(libc::EIO)
+            }
+        }
+    }
+
+    fn readdir(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        let Some(inode) = self.inodes.get(&ino) else {
+            return reply.error(libc::ENOENT);
+        };
+        let InodeKind::Dir { children } = &inode.kind else {
+            return reply.error(libc::ENOTDIR);
+        };
+
+        let entries = [(ino, FileType::Directory, OsString::from(".")), (inode.parent, FileType::Directory, OsString::from(".."))]
+            .into_iter()
+            .chain(children.iter().map(|(name, &child)| {
+                let kind = match self.inodes.get(&child).map(|x| &x.kind) {
+                    Some(InodeKind::File { .. }) => FileType::RegularFile,
+                    _ => FileType::Directory,
+                };
+                (child, kind, name.clone())
+            }));
+
+        for (i, (child, kind, name)) in entries.enumerate().skip(offset as usize) {
+            // The offset passed back is the one of the next entry.
+            if reply.add(child, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
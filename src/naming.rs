@@ -0,0 +1,275 @@
+//! File names of the backups.
+//!
+//! Backups are named after a template such as the default
+//! `backup-{profile}-{hostname}-{timestamp}-{seq}.{format}`, and the same template
+//! parses the names back, which tells which files in the backup folder are backups
+//! and which job made them. Names of older versions, `backup2024-01-01.tar.gz`, are
+//! still recognized as backups of the default profile.
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::error::InvalidNamingTemplateError;
+
+pub const DEFAULT_TEMPLATE: &str = "backup-{profile}-{hostname}-{timestamp}-{seq}.{format}";
+/// Profile of the backups whose name doesn't tell.
+pub const DEFAULT_PROFILE: &str = "default";
+/// Format of `{timestamp}`, always in UTC, e.g. `20240101T093000Z`.
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+const TIMESTAMP_LEN: usize = "20240101T093000Z".len();
+/// Archive formats `{format}` may stand for.
+const FORMATS: [&str; 1] = ["tar.gz"];
+
+/// What to do if a backup with the same name already exists, e.g. if the template
+/// has no `{seq}` and two backups are started within the same second.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CollisionPolicy {
+    /// Number the new backup with the next free `{seq}`.
+    #[default]
+    NewSequence,
+    /// Delete the existing backup, and its index, once the new one is uploaded.
+    Replace,
+    /// Don't make the backup.
+    Fail,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Literal(String),
+    Profile,
+    Hostname,
+    Timestamp,
+    Seq,
+    Format,
+}
+
+/// The parts a backup's name is made of.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BackupName {
+    pub profile: Option<String>,
+    pub hostname: Option<String>,
+    /// When the backup was started. `None` for names of older versions, which only
+    /// hold the date.
+    pub timestamp: Option<DateTime<Utc>>,
+    /// Number of the backup among the backups of the same job on the same day, from 1.
+    pub seq: u32,
+    pub format: String,
+}
+
+impl BackupName {
+    /// Returns the profile the backup belongs to.
+    pub fn profile(&self) -> &str {
+        self.profile.as_deref().unwrap_or(DEFAULT_PROFILE)
+    }
+}
+
+/// Template of the backups' names, made of `{profile}`, `{hostname}`, `{timestamp}`,
+/// `{seq}` and `{format}` placeholders and the text between them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct NamingTemplate {
+    template: String,
+    tokens: Vec<Token>,
+}
+
+impl Default for NamingTemplate {
+    fn default() -> Self {
+        NamingTemplate::new(DEFAULT_TEMPLATE).unwrap()
+    }
+}
+
+impl TryFrom<String> for NamingTemplate {
+    type Error = InvalidNamingTemplateError;
+
+    fn try_from(template: String) -> Result<Self, Self::Error> {
+        NamingTemplate::new(&template)
+    }
+}
+
+impl From<NamingTemplate> for String {
+    fn from(template: NamingTemplate) -> Self {
+        template.template
+    }
+}
+
+/// Replaces the characters that can't be part of a name, or could be mistaken for
+/// the separators of a template, with `_`.
+/// E.g.: "my-laptop" ----> "my_laptop"
+pub fn sanitize(value: &str) -> String {
+    value.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' { c } else { '_' })
+        .collect()
+}
+
+impl NamingTemplate {
+    /// Parses a template.
+    ///
+    /// # Errors
+    ///
+    /// * `InvalidNamingTemplateError` if a placeholder is unknown or unclosed, if
+    ///   `{timestamp}` or `{format}` is missing, if two placeholders aren't separated by
+    ///   any text, or if the template contains a path separator.
+    pub fn new(template: &str) -> Result<Self, InvalidNamingTemplateError> {
+        let invalid = |reason: &str| InvalidNamingTemplateError {
+            template: String::from(template),
+            reason: String::from(reason),
+        };
+
+        if template.contains(['/', '\\']) {
+            return Err(invalid("Names can't contain path separators."));
+        }
+
+        let mut tokens = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                tokens.push(Token::Literal(String::from(&rest[..start])));
+            } else if tokens.last().is_some_and(|x| !matches!(x, Token::Literal(_))) {
+                return Err(invalid("Placeholders must be separated by some text, e.g. `-`."));
+            }
+
+            let end = rest[start..].find('}').ok_or_else(|| invalid("Unclosed `{`."))? + start;
+            tokens.push(match &rest[start + 1..end] {
+                "profile" => Token::Profile,
+                "hostname" => Token::Hostname,
+                "timestamp" => Token::Timestamp,
+                "seq" => Token::Seq,
+                "format" => Token::Format,
+                other => return Err(invalid(&format!(
+                    "Unknown placeholder `{{{}}}`, use `{{profile}}`, `{{hostname}}`, `{{timestamp}}`, `{{seq}}` or `{{format}}`.", other
+                ))),
+            });
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            tokens.push(Token::Literal(String::from(rest)));
+        }
+
+        for required in [Token::Timestamp, Token::Format] {
+            if !tokens.contains(&required) {
+                return Err(invalid(&format!("`{}` is required.", match required {
+                    Token::Timestamp => "{timestamp}",
+                    _ => "{format}",
+                })));
+            }
+        }
+
+        Ok(NamingTemplate { template: String::from(template), tokens })
+    }
+
+    /// Whether names made with the template are numbered.
+    pub fn has_seq(&self) -> bool {
+        self.tokens.contains(&Token::Seq)
+    }
+
+    /// Whether names made with the template tell which job made the backup.
+    pub fn has_profile(&self) -> bool {
+        self.tokens.contains(&Token::Profile)
+    }
+
+    /// Returns the name of a backup.
+    /// E.g.: `backup-{profile}-{timestamp}.{format}` ----> `backup-documents-20240101T093000Z.tar.gz`
+    pub fn render(&self, name: &BackupName) -> String {
+        self.tokens.iter()
+            .map(|token| match token {
+                Token::Literal(text) => text.clone(),
+                Token::Profile => sanitize(name.profile()),
+                Token::Hostname => sanitize(name.hostname.as_deref().unwrap_or("unknown")),
+                Token::Timestamp => name.timestamp.unwrap_or_else(Utc::now).format(TIMESTAMP_FORMAT).to_string(),
+                Token::Seq => name.seq.to_string(),
+                Token::Format => name.format.clone(),
+            })
+            .collect()
+    }
+
+    /// Parses the name of a backup made with this template, or with an older version.
+    ///
+    /// # Returns
+    ///
+    /// Returns `None` if the file isn't a backup.
+    pub fn parse(&self, file_name: &str) -> Option<BackupName> {
+        let mut name = BackupName { profile: None, hostname: None, timestamp: None, seq: 1, format: String::new() };
+        if parse_tokens(&self.tokens, file_name, &mut name) {
+            return Some(name);
+        }
+        parse_legacy(file_name)
+    }
+
+    /// Whether `file_name` is a backup of `profile`.
+    ///
+    /// Without `{profile}` in the names, every backup belongs to the profile, since
+    /// jobs may only share a folder if their names tell them apart.
+    pub fn is_backup_of(&self, file_name: &str, profile: &str) -> bool {
+        self.parse(file_name).is_some_and(|name| !self.has_profile() || sanitize(name.profile()) == sanitize(profile))
+    }
+}
+
+/// Parses `backup2024-01-01.tar.gz`, the names used before templates.
+fn parse_legacy(file_name: &str) -> Option<BackupName> {
+    let date = file_name.strip_prefix("backup")?.strip_suffix(".tar.gz")?;
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+
+    Some(BackupName { profile: None, hostname: None, timestamp: None, seq: 1, format: String::from("tar.gz") })
+}
+
+/// Matches `rest` against `tokens`, filling in `name`. Placeholders of free-form text
+/// take the shortest match after which the rest of the name still matches.
+fn parse_tokens(tokens: &[Token], rest: &str, name: &mut BackupName) -> bool {
+    let Some((token, tokens)) = tokens.split_first() else {
+        return rest.is_empty();
+    };
+
+    match token {
+        Token::Literal(text) => match rest.strip_prefix(text.as_str()) {
+            Some(rest) => parse_tokens(tokens, rest, name),
+            None => false,
+        },
+        Token::Timestamp => {
+            let Some(timestamp) = rest.get(..TIMESTAMP_LEN) else {
+                return false;
+            };
+            match NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT) {
+                Ok(timestamp) => {
+                    name.timestamp = Some(timestamp.and_utc());
+                    parse_tokens(tokens, &rest[TIMESTAMP_LEN..], name)
+                }
+                Err(_) => false,
+            }
+        }
+        Token::Seq => {
+            let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+            match rest[..digits].parse() {
+                Ok(seq) => {
+                    name.seq = seq;
+                    parse_tokens(tokens, &rest[digits..], name)
+                }
+                Err(_) => false,
+            }
+        }
+        Token::Format => FORMATS.iter().any(|format| match rest.strip_prefix(format) {
+            Some(after) if parse_tokens(tokens, after, name) => {
+                name.format = String::from(*format);
+                true
+            }
+            _ => false,
+        }),
+        Token::Profile | Token::Hostname => {
+            if rest.is_empty() {
+                return false;
+            }
+            for (end, _) in rest.char_indices().skip(1).chain(std::iter::once((rest.len(), ' '))) {
+                if parse_tokens(tokens, &rest[end..], name) {
+                    let value = Some(String::from(&rest[..end]));
+                    if *token == Token::Profile {
+                        name.profile = value;
+                    } else {
+                        name.hostname = value;
+                    }
+                    return true;
+                }
+            }
+            false
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Where backuprs keeps its own files.
+//!
+//! On Unix the XDG base directories are followed: the settings are looked for in
+//! `~/.config/backuprs`, the log is written to `~/.local/state/backuprs`, and archives
+//! are built in `~/.cache/backuprs`, unless `XDG_CONFIG_HOME`, `XDG_STATE_HOME` or
+//! `XDG_CACHE_HOME` point elsewhere. On Windows `%APPDATA%` and `%LOCALAPPDATA%` are
+//! used instead. Nothing depends on the working directory, so runs from cron or
+//! systemd behave the same as runs from a terminal.
+
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use crate::utils;
+
+const APP_NAME: &str = "backuprs";
+
+/// Looks up an environment variable. The folders are found through one, so that
+/// tests can give them their own environment instead of changing the process's.
+pub type Env<'a> = &'a dyn Fn(&str) -> Option<OsString>;
+
+/// The environment of the process.
+pub fn process_env(var: &str) -> Option<OsString> {
+    std::env::var_os(var)
+}
+
+/// Returns the user's home directory in the environment `env`.
+pub fn home_dir_in(env: Env) -> Option<PathBuf> {
+    let var = if cfg!(target_os = "windows") { "USERPROFILE" } else { "HOME" };
+    env(var).filter(|x| !x.is_empty()).map(PathBuf::from)
+}
+
+/// Reads a variable holding a base directory. Relative paths are ignored, as the
+/// XDG specification requires.
+fn dir_from_env(env: Env, var: &str) -> Option<PathBuf> {
+    env(var).map(PathBuf::from).filter(|x| x.is_absolute())
+}
+
+/// Returns backuprs' folder in a base directory.
+///
+/// # Arguments
+///
+/// * `env` - The environment to read the variables from.
+/// * `xdg_var` - XDG variable of the base directory.
+/// * `home_fallback` - The base directory relative to the home directory, if `xdg_var`
+///   isn't set.
+/// * `windows_var` - Variable of the base directory on Windows.
+fn app_dir(env: Env, xdg_var: &str, home_fallback: &str, windows_var: &str) -> Option<PathBuf> {
+    let base = if cfg!(target_os = "windows") {
+        dir_from_env(env, windows_var)
+    } else {
+        dir_from_env(env, xdg_var).or_else(|| home_dir_in(env).map(|home| home.join(home_fallback)))
+    };
+    base.map(|x| x.join(APP_NAME))
+}
+
+/// Folder the settings are looked for in, after the working directory.
+/// E.g.: `~/.config/backuprs`
+pub fn config_dir() -> Option<PathBuf> {
+    config_dir_in(&process_env)
+}
+
+/// Folder the settings are looked for in, in the environment `env`.
+pub fn config_dir_in(env: Env) -> Option<PathBuf> {
+    app_dir(env, "XDG_CONFIG_HOME", ".config", "APPDATA")
+}
+
+/// Folder of the log. Falls back to the working directory.
+/// E.g.: `~/.local/state/backuprs`
+pub fn state_dir() -> PathBuf {
+    app_dir(&process_env, "XDG_STATE_HOME", ".local/state", "LOCALAPPDATA").unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// Folder of the archives being uploaded or restored, and of the files of mounted
+/// backups. Falls back to the system's temporary directory.
+/// E.g.: `~/.cache/backuprs`
+pub fn cache_dir() -> PathBuf {
+    cache_dir_in(&process_env)
+}
+
+/// Folder of the archives, in the environment `env`.
+pub fn cache_dir_in(env: Env) -> PathBuf {
+    let dir = if cfg!(target_os = "windows") {
+        app_dir(env, "XDG_CACHE_HOME", ".cache", "LOCALAPPDATA").map(|x| x.join("cache"))
+    } else {
+        app_dir(env, "XDG_CACHE_HOME", ".cache", "LOCALAPPDATA")
+    };
+    dir.unwrap_or_else(|| std::env::temp_dir().join(APP_NAME))
+}
+
+fn env_var(env: Env, name: &str) -> Result<String, String> {
+    env(name).and_then(|x| x.into_string().ok()).ok_or_else(|| format!("the environment variable `{}` isn't set", name))
+}
+
+/// Expands a leading `~`, `$VAR`, `${VAR}` and `{hostname}` in a path from the
+/// settings, so that the same settings work on several machines. `$$` stands for `$`.
+/// The variables are read from the environment `env`.
+/// E.g.: "~/Documents/${PROJECT}-{hostname}" ----> "/home/me/Documents/notes-laptop"
+///
+/// # Errors
+///
+/// Returns why the path can't be expanded, e.g. a variable isn't set.
+pub fn expand_in(value: &str, env: Env) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = value;
+
+    if rest == "~" || rest.starts_with("~/") || rest.starts_with("~\\") {
+        let home = home_dir_in(env).ok_or_else(|| String::from("the home directory isn't known"))?;
+        expanded.push_str(&home.to_string_lossy());
+        rest = &rest[1..];
+    }
+
+    while let Some(i) = rest.find(['$', '{']) {
+        expanded.push_str(&rest[..i]);
+        rest = &rest[i..];
+
+        if let Some(after) = rest.strip_prefix("{hostname}") {
+            expanded.push_str(&utils::hostname());
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("$$") {
+            expanded.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after.find('}').ok_or_else(|| String::from("`${` isn't closed"))?;
+            expanded.push_str(&env_var(env, &after[..end])?);
+            rest = &after[end + 1..];
+        } else if let Some(after) = rest.strip_prefix('$') {
+            let len = after.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(after.len());
+            if len > 0 {
+                expanded.push_str(&env_var(env, &after[..len])?);
+            } else {
+                expanded.push('$');
+            }
+            rest = &after[len..];
+        } else {
+            // Any other `{` is part of the path.
+            expanded.push('{');
+            rest = &rest[1..];
+        }
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
@@ -0,0 +1,79 @@
+//! Backups protected from pruning.
+//!
+//! Pins are kept in a small JSON file in the backup folder, next to the backups
+//! themselves, so every machine using the same account sees the same pins.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::snapshot::OutputFormat;
+
+/// Name of the file holding the pins in the backup folder.
+pub const PINS_FILE_NAME: &str = "backuprs-pins.json";
+
+/// Why a backup is pinned.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Pin {
+    /// Free-form label, e.g. `before-migration`.
+    pub tag: Option<String>,
+    pub note: Option<String>,
+    pub pinned_at: DateTime<Utc>,
+}
+
+/// Pinned backups by their archive's file name.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Pins {
+    pub pins: BTreeMap<String, Pin>,
+}
+
+impl Pins {
+    pub fn from_slice(contents: &[u8]) -> Result<Self, serde_json::Error> {
+        serde_json::from_slice(contents)
+    }
+
+    pub fn to_vec(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec_pretty(self)
+    }
+
+    pub fn get(&self, archive: &str) -> Option<&Pin> {
+        self.pins.get(archive)
+    }
+
+    /// Pins a backup, replacing its tag and note if it is already pinned.
+    pub fn pin(&mut self, archive: &str, tag: Option<String>, note: Option<String>) {
+        self.pins.insert(String::from(archive), Pin { tag, note, pinned_at: Utc::now() });
+    }
+
+    /// Unpins a backup, returning whether it was pinned.
+    pub fn unpin(&mut self, archive: &str) -> bool {
+        self.pins.remove(archive).is_some()
+    }
+}
+
+/// A backup pinned or unpinned by `pin` or `unpin`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PinChange {
+    pub archive: String,
+    /// Whether the backup is pinned now.
+    pub pinned: bool,
+    /// Whether the pins changed, `false` if a backup that wasn't pinned was unpinned.
+    pub changed: bool,
+    pub tag: Option<String>,
+    pub note: Option<String>,
+}
+
+/// Prints a change of the pins to stdout in the given format.
+pub fn print_change(change: &PinChange, format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(change)?),
+        OutputFormat::Table => match (change.pinned, change.changed) {
+            (true, _) => println!("Pinned {}.", change.archive),
+            (false, true) => println!("Unpinned {}.", change.archive),
+            (false, false) => println!("{} was not pinned.", change.archive),
+        },
+    }
+
+    Ok(())
+}
@@ -0,0 +1,150 @@
+//! Previewing a backup without making it.
+//!
+//! `backup --dry-run` walks the folders of a job the same way a backup does, leaving
+//! out the ignored folders and the files over the size cap, and tells what would be
+//! archived. The compressed size is estimated by compressing the start of every file,
+//! so nothing is written to disk and MEGA isn't contacted.
+
+use std::fs::File;
+use std::io::{Read, Write};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use serde::Serialize;
+
+use crate::config::Job;
+use crate::snapshot::OutputFormat;
+use crate::utils;
+use crate::walker::DirWalker;
+
+const BYTES_IN_MB: u64 = 1048576;
+/// Bytes of every file compressed to estimate the size of the archive.
+const SAMPLE_SIZE: u64 = 64 * 1024;
+
+/// Whether a file is too large to be backed up, see `max_file_mb`.
+pub fn exceeds_size_cap(size: u64, max_file_mb: u64) -> bool {
+    size / BYTES_IN_MB > max_file_mb
+}
+
+/// Why a file or folder would be left out of the backup.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExclusionReason {
+    /// The folder is one of `dirs_to_ignore`.
+    IgnoredFolder,
+    /// The file is larger than `max_file_mb`.
+    TooLarge,
+}
+
+/// A file that would be archived.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct IncludedFile {
+    pub path: String,
+    pub size: u64,
+}
+
+/// A file or folder that would be left out.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ExcludedFile {
+    pub path: String,
+    /// Size of the file, 0 for ignored folders.
+    pub size: u64,
+    pub reason: ExclusionReason,
+}
+
+/// What a job would back up.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BackupPlan {
+    pub job: String,
+    pub included: Vec<IncludedFile>,
+    pub excluded: Vec<ExcludedFile>,
+    /// Size of the included files in bytes.
+    pub total_size: u64,
+    /// Rough size of the archive in bytes.
+    pub estimated_compressed_size: u64,
+}
+
+/// Walks the folders of a job and tells which files would be backed up.
+///
+/// # Errors
+///
+/// * Any error while listing a folder or reading a file.
+pub fn plan_job(job: &Job) -> Result<BackupPlan, Box<dyn std::error::Error>> {
+    let mut plan = BackupPlan {
+        job: job.name.clone(),
+        included: Vec::new(),
+        excluded: Vec::new(),
+        total_size: 0,
+        estimated_compressed_size: 0,
+    };
+
+    for dir_path in job.dirs_to_backup.iter() {
+        let dir_contents = DirWalker::new(dir_path)
+            .ignore_folders(Some(job.dirs_to_ignore.clone()))
+            .report_ignored(true)
+            .walk();
+
+        for entry in dir_contents {
+            let entry = entry?;
+            let path = entry.path.to_string_lossy().to_string();
+
+            if entry.ignored {
+                plan.excluded.push(ExcludedFile { path, size: 0, reason: ExclusionReason::IgnoredFolder });
+            } else if exceeds_size_cap(entry.size, job.max_file_mb) {
+                plan.excluded.push(ExcludedFile { path, size: entry.size, reason: ExclusionReason::TooLarge });
+            } else {
+                plan.total_size += entry.size;
+                plan.estimated_compressed_size += estimate_compressed_size(&entry.path, entry.size)?;
+                plan.included.push(IncludedFile { path, size: entry.size });
+            }
+        }
+    }
+
+    Ok(plan)
+}
+
+/// Estimates the compressed size of a file by compressing up to `SAMPLE_SIZE` bytes
+/// of it the same way the archive is compressed.
+fn estimate_compressed_size(path: &std::path::Path, size: u64) -> Result<u64, Box<dyn std::error::Error>> {
+    let mut sample = Vec::new();
+    File::open(path)?.take(SAMPLE_SIZE).read_to_end(&mut sample)?;
+    if sample.is_empty() {
+        return Ok(0);
+    }
+
+    let mut encoder = GzEncoder::new(utils::CountingWriter::new(std::io::sink()), Compression::best());
+    encoder.write_all(&sample)?;
+    let compressed = encoder.finish()?.count();
+
+    Ok((compressed as f64 / sample.len() as f64 * size as f64) as u64)
+}
+
+/// Prints the plans to stdout in the given format.
+pub fn print_plans(plans: &[BackupPlan], format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(plans)?),
+        OutputFormat::Table => {
+            for plan in plans {
+                println!("Job {}:", plan.job);
+                for file in plan.included.iter() {
+                    println!("  + {}  ({})", file.path, utils::format_size(file.size));
+                }
+                for file in plan.excluded.iter() {
+                    match file.reason {
+                        ExclusionReason::IgnoredFolder => println!("  - {}  (ignored folder)", file.path),
+                        ExclusionReason::TooLarge => println!("  - {}  ({}, too large)", file.path, utils::format_size(file.size)),
+                    }
+                }
+                println!(
+                    "  {} files included ({}), {} excluded, about {} compressed",
+                    plan.included.len(),
+                    utils::format_size(plan.total_size),
+                    plan.excluded.len(),
+                    utils::format_size(plan.estimated_compressed_size)
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,394 @@
+//! Restoring backups created by `create_tarball_from_dirs`.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use log::{debug, error, info, warn};
+use serde::Serialize;
+
+use crate::check;
+use crate::error::CorruptBackupError;
+use crate::manifest::{self, Manifest};
+use crate::snapshot::OutputFormat;
+use crate::utils;
+
+/// Options of a single restore run.
+#[derive(Debug, Clone)]
+pub struct RestoreOptions {
+    /// Name of the backup, its date (`YYYY-MM-DD`) or `latest`.
+    pub snapshot: String,
+    /// Directory that the backed up folders are restored into. If it is `None`, every
+    /// folder is restored to its original location listed in `dirs_to_backup`.
+    pub target: Option<PathBuf>,
+    /// Paths or glob patterns of the entries to restore. If it is empty, every entry is
+    /// restored. See `PathFilter` for how they are matched.
+    pub filters: Vec<String>,
+    /// What to do with files that already exist at the restored location.
+    pub conflict: ConflictPolicy,
+}
+
+/// What to do when a restored file already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ConflictPolicy {
+    /// Keep the existing file.
+    Skip,
+    /// Replace the existing file.
+    #[default]
+    Overwrite,
+    /// Restore next to the existing file, as e.g. `todo (restored).txt`.
+    Rename,
+    /// Replace the existing file only if the archived one was modified later.
+    IfNewer,
+}
+
+/// Number of the archive's entries, by what happened to them during a restore.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RestoreSummary {
+    pub restored: usize,
+    /// Entries restored under a new name, also counted in `restored`.
+    pub renamed: usize,
+    /// Entries not restored because of the `ConflictPolicy`.
+    pub skipped: usize,
+    /// Links, and entries with paths leading out of the restore directory.
+    pub rejected: usize,
+    /// Restored files that don't match the manifest embedded in the archive, also
+    /// counted in `restored`.
+    pub corrupt: usize,
+}
+
+/// Prints what a restore of `archive` did to stdout in the given format.
+pub fn print_summary(archive: &str, summary: &RestoreSummary, format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    #[derive(Serialize)]
+    struct Report<'a> {
+        archive: &'a str,
+        #[serde(flatten)]
+        summary: &'a RestoreSummary,
+    }
+
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&Report { archive, summary })?),
+        OutputFormat::Table => println!(
+            "Restored {} file(s) from {} ({} renamed, {} skipped, {} rejected, {} corrupt).",
+            summary.restored, archive, summary.renamed, summary.skipped, summary.rejected, summary.corrupt
+        ),
+    }
+
+    Ok(())
+}
+
+/// Selects entries of an archive by their path, e.g. `backup_folder/notes/todo.txt`.
+#[derive(Debug, Clone)]
+pub enum PathFilter {
+    /// Matches the path itself and everything below it.
+    Prefix(String),
+    /// Matches paths matching the glob pattern. `*` also matches path separators,
+    /// so `*.txt` selects text files at any depth.
+    Glob(glob::Pattern),
+}
+
+impl PathFilter {
+    /// Parses a filter, treating it as a glob pattern if it contains any of `*?[`.
+    pub fn parse(filter: &str) -> Result<Self, glob::PatternError> {
+        let normalized = archive_path_components(filter).join("/");
+        if filter.contains(['*', '?', '[']) {
+            Ok(PathFilter::Glob(glob::Pattern::new(&normalized)?))
+        } else {
+            Ok(PathFilter::Prefix(normalized))
+        }
+    }
+
+    /// Returns whether `path` (with `/` separators) is selected by the filter.
+    pub fn matches(&self, path: &str) -> bool {
+        match self {
+            PathFilter::Prefix(prefix) => {
+                path == prefix || path.starts_with(&format!("{}/", prefix))
+            }
+            PathFilter::Glob(pattern) => pattern.matches(path),
+        }
+    }
+}
+
+/// Splits a path stored in the archive into its components.
+///
+/// Older archives were created with backslash separated paths, which are split on
+/// backslashes if the path has no `/`. Newer archives join paths with `/`, and a
+/// backslash in them is part of a name, as it may be on Unix.
+/// E.g.: "backup_folder\\notes\\todo.txt" ----> ["backup_folder", "notes", "todo.txt"]
+pub(crate) fn archive_path_components(path: &str) -> Vec<&str> {
+    let separators: &[char] = if path.contains('/') { &['/'] } else { &['/', '\\'] };
+    path.split(separators)
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect()
+}
+
+/// Returns the name of the folder a backed up directory is stored as in the archive.
+/// E.g.: "C:\\Users\\username\\Documents\\backup_folder" ----> "backup_folder"
+pub(crate) fn backup_folder_name(dir_path: &str) -> Option<&str> {
+    dir_path.split(['/', '\\']).rfind(|component| !component.is_empty() && *component != ".")
+}
+
+/// Returns the path a file is stored at in the archive, which `extract_archive` reverses.
+/// It is the name of the backed up folder followed by the file's path inside it,
+/// joined by `/`, so the archive can be restored on any platform.
+/// E.g.: "C:\\Users\\username\\Documents\\backup_folder\\notes\\todo.txt"
+/// ----> "backup_folder/notes/todo.txt"
+pub(crate) fn archive_path(dir_path: &str, file_path: &Path) -> String {
+    let relative_path = file_path.strip_prefix(dir_path).unwrap_or(file_path);
+
+    std::iter::once(backup_folder_name(dir_path).unwrap_or("backup").to_string())
+        .chain(relative_path.components().filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+            _ => None,
+        }))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Checks a path stored in the archive, returning its components if it is safe to
+/// restore. Paths of older archives separated by backslashes are accepted as well.
+///
+/// # Returns
+///
+/// Returns `None` for absolute paths (e.g. `/etc/passwd`, `\\server\\share` or `C:\\`),
+/// for paths containing `..`, and for paths with a drive in any component (e.g.
+/// `notes/C:/Windows` or `notes/d:todo.txt`), which could lead out of the restore
+/// directory. On Windows, where a `:` names an alternate data stream, paths with a
+/// `:` anywhere are rejected too; elsewhere e.g. `notes/10:30 meeting.txt` is fine.
+pub fn sanitize_archive_path(path: &str) -> Option<Vec<&str>> {
+    if path.starts_with(['/', '\\']) {
+        return None;
+    }
+
+    let components = archive_path_components(path);
+    // Joining a drive, e.g. the `C:` of `notes/C:/Windows`, replaces the whole path
+    // on Windows, so no component may start with one.
+    let is_drive = |component: &str| {
+        let bytes = component.as_bytes();
+        bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+    };
+    let is_safe = |component: &str| {
+        !is_drive(component)
+            && (!cfg!(target_os = "windows") || !component.contains(':'))
+            && matches!(Path::new(component).components().collect::<Vec<_>>().as_slice(), [Component::Normal(_)])
+    };
+
+    if !components.iter().all(|x| is_safe(x)) {
+        return None;
+    }
+
+    Some(components)
+}
+
+/// Whether any folder between `root` and `destination` is a symlink, which writing
+/// `destination` would follow, possibly out of the restore directory.
+fn has_symlinked_ancestor(root: &Path, destination: &Path) -> bool {
+    let Some(parent) = destination.parent() else {
+        return false;
+    };
+    let Ok(relative) = parent.strip_prefix(root) else {
+        return true;
+    };
+
+    let mut path = root.to_path_buf();
+    relative.components().any(|component| {
+        path.push(component);
+        path.symlink_metadata().is_ok_and(|x| x.file_type().is_symlink())
+    })
+}
+
+/// Returns a free path next to `path` for a restored file, e.g. `todo (restored).txt`.
+fn renamed_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().map(|x| x.to_string_lossy().to_string()).unwrap_or_default();
+    let extension = path.extension().map(|x| format!(".{}", x.to_string_lossy())).unwrap_or_default();
+
+    (1..)
+        .map(|i| {
+            let suffix = if i == 1 { String::from("restored") } else { format!("restored {}", i) };
+            path.with_file_name(format!("{} ({}){}", stem, suffix, extension))
+        })
+        .find(|candidate| !candidate.exists())
+        .expect("There must be a free file name.")
+}
+
+/// Reads through the whole archive, making sure that it can be decompressed and
+/// that every entry matches the manifest embedded in the archive.
+///
+/// # Returns
+///
+/// Returns the number of files found in the archive.
+///
+/// # Errors
+///
+/// * `CorruptBackupError` if the archive is truncated, can't be decompressed, or
+///   any of its files doesn't match the embedded manifest.
+pub fn verify_tarball(file_name: &str) -> Result<usize, Box<dyn std::error::Error>> {
+    let report = check::check_archive(File::open(file_name)?, file_name);
+
+    if !report.is_ok() {
+        return Err(CorruptBackupError {
+            file_name: String::from(file_name),
+            reason: report.problems.join("; "),
+        }.into());
+    }
+
+    Ok(report.entries)
+}
+
+/// Extracts an archive created by `create_tarball_from_dirs`, reversing its path mapping.
+///
+/// See `extract_archive` for how the archived paths are mapped.
+pub fn extract_tarball(file_name: &str, target: Option<&Path>, dirs_to_backup: &[String], filters: &[PathFilter], conflict: ConflictPolicy) -> Result<RestoreSummary, Box<dyn std::error::Error>> {
+    let file = File::open(file_name)?;
+    Ok(extract_archive(file, target, dirs_to_backup, filters, conflict)?)
+}
+
+/// Extracts a gzipped tar stream, restoring only the entries selected by `filters`.
+///
+/// Every archived path starts with the name of the backed up folder, e.g.
+/// `backup_folder/notes/todo.txt`. With a `target`, it is restored to
+/// `target/backup_folder/notes/todo.txt`. Without one, the folder of `dirs_to_backup`
+/// with the same name is looked up and the file is restored to its original location.
+///
+/// Only regular files and directories are restored. Entries with unsafe paths (see
+/// `sanitize_archive_path`), links, and entries that would be written through a
+/// symlinked folder are rejected. Existing files are handled as `conflict` says.
+///
+/// The stream is read only once, from start to end, so `reader` may be a download
+/// that is still in progress. Since the manifest is only found at the end, every
+/// restored file is read back once the archive is through and compared with it,
+/// counting those that don't match in `RestoreSummary::corrupt`.
+pub fn extract_archive<R: Read>(reader: R, target: Option<&Path>, dirs_to_backup: &[String], filters: &[PathFilter], conflict: ConflictPolicy) -> io::Result<RestoreSummary> {
+    let mut archive = tar::Archive::new(GzDecoder::new(reader));
+    let mut summary = RestoreSummary::default();
+    let mut embedded: Option<Manifest> = None;
+    // Archived path and destination of every restored file.
+    let mut restored = Vec::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let archived_path = String::from_utf8_lossy(&entry.path_bytes()).to_string();
+
+        let Some(components) = sanitize_archive_path(&archived_path) else {
+            error!("Rejecting entry with unsafe path: {:?}", archived_path);
+            summary.rejected += 1;
+            continue;
+        };
+
+        // Links could point anywhere, and later entries could be written through them.
+        let entry_type = entry.header().entry_type();
+        if !(entry_type.is_file() || entry_type.is_dir()) {
+            error!("Rejecting entry that is not a file or a directory: {:?}", archived_path);
+            summary.rejected += 1;
+            continue;
+        }
+
+        let joined_path = components.join("/");
+        if joined_path == manifest::EMBEDDED_MANIFEST_PATH {
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            embedded = serde_json::from_slice(&contents).map_err(|e| warn!("Couldn't read the manifest of the archive: {:?}", e)).ok();
+            continue;
+        }
+
+        if !filters.is_empty() && !filters.iter().any(|filter| filter.matches(&joined_path)) {
+            continue;
+        }
+
+        let Some((folder_name, rest)) = components.split_first() else {
+            continue;
+        };
+
+        let root = match target {
+            Some(target) => target.join(folder_name),
+            None => match dirs_to_backup.iter().find(|dir| backup_folder_name(dir) == Some(folder_name)) {
+                Some(dir) => PathBuf::from(dir),
+                None => {
+                    warn!("No directory to restore {:?} into, skipping it.", archived_path);
+                    summary.skipped += 1;
+                    continue;
+                }
+            },
+        };
+
+        let mut destination = rest.iter().fold(root.clone(), |path, component| path.join(component));
+
+        if destination.symlink_metadata().is_ok() {
+            match conflict {
+                ConflictPolicy::Overwrite => (),
+                ConflictPolicy::Skip => {
+                    debug!("Skipping existing file {:?}", destination);
+                    summary.skipped += 1;
+                    continue;
+                }
+                ConflictPolicy::Rename => {
+                    destination = renamed_path(&destination);
+                    summary.renamed += 1;
+                }
+                ConflictPolicy::IfNewer => {
+                    let archived_mtime = entry.header().mtime().unwrap_or(0);
+                    let existing_mtime = destination.metadata()?.modified()?
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|x| x.as_secs())
+                        .unwrap_or(0);
+                    if archived_mtime <= existing_mtime {
+                        debug!("Skipping {:?}, the existing file is not older.", destination);
+                        summary.skipped += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+
+        if has_symlinked_ancestor(&root, &destination) {
+            error!("Rejecting entry that would be written through a symlink: {:?}", archived_path);
+            summary.rejected += 1;
+            continue;
+        }
+
+        if let Some(parent) = destination.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        debug!("Restoring {:?} to {:?}", archived_path, destination);
+        entry.unpack(&destination)?;
+        summary.restored += 1;
+        if entry_type.is_file() {
+            restored.push((joined_path, destination));
+        }
+    }
+
+    // Read the stream to its end, so that the gzip checksum is verified and
+    // a download feeding `reader` is never left waiting.
+    io::copy(&mut archive.into_inner(), &mut io::sink())?;
+
+    match embedded {
+        Some(embedded) => {
+            let expected: HashMap<&str, &manifest::ManifestEntry> = embedded.entries.iter()
+                .map(|entry| (entry.path.as_str(), entry))
+                .collect();
+            for (path, destination) in restored {
+                let mut hashing_reader = utils::HashingReader::new(File::open(&destination)?);
+                let size = io::copy(&mut hashing_reader, &mut io::sink())?;
+                let sha256 = hashing_reader.hex_digest();
+
+                let matches = expected.get(path.as_str())
+                    .is_some_and(|entry| entry.size == size && entry.sha256.as_ref().is_none_or(|x| *x == sha256));
+                if !matches {
+                    error!("Restored {:?} doesn't match the manifest of the archive.", destination);
+                    summary.corrupt += 1;
+                }
+            }
+        }
+        None if !restored.is_empty() => warn!("The archive has no embedded manifest, the restored files weren't verified."),
+        None => (),
+    }
+
+    info!(
+        "Restored {:?} file(s) ({:?} renamed, {:?} corrupt), skipped {:?}, rejected {:?}.",
+        summary.restored, summary.renamed, summary.corrupt, summary.skipped, summary.rejected
+    );
+    Ok(summary)
+}
@@ -0,0 +1,305 @@
+//! Deciding which backups to keep, in the manner of `restic forget` and `borg prune`.
+//!
+//! Each rule keeps some of the backups, and a backup is removed only if no rule keeps
+//! it. The `keep_daily`, `keep_weekly`, `keep_monthly` and `keep_yearly` rules keep the
+//! newest backup of the given number of most recent days, weeks, months and years
+//! that have a backup.
+//!
+//! On top of the rules, a size budget removes the oldest of the kept backups until the
+//! backups fit into it, so that the account never fills up and uploads start failing.
+
+use chrono::{DateTime, Datelike, Duration, Local, TimeZone, Utc};
+use log::warn;
+use mega::StorageQuotas;
+use serde::{Deserialize, Serialize};
+
+use crate::error::InvalidRetentionError;
+use crate::utils;
+
+/// Maps a backup's local creation date to the period it belongs to, e.g. `2024-03`.
+type BucketFn = fn(&DateTime<Local>) -> String;
+
+/// Rules of which backups to keep. Rules that are `None` keep nothing.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionPolicy {
+    /// Keep the given number of newest backups.
+    pub keep_last: Option<usize>,
+    /// Keep the newest backup of the given number of most recent days.
+    pub keep_daily: Option<usize>,
+    /// Keep the newest backup of the given number of most recent ISO weeks.
+    pub keep_weekly: Option<usize>,
+    /// Keep the newest backup of the given number of most recent months.
+    pub keep_monthly: Option<usize>,
+    /// Keep the newest backup of the given number of most recent years.
+    pub keep_yearly: Option<usize>,
+    /// Keep every backup made within this duration before the newest backup, e.g.
+    /// `2w3d`. Units are `h`ours, `d`ays, `w`eeks, `m`onths (30 days) and `y`ears (365 days).
+    pub keep_within: Option<String>,
+    /// Maximum total size of the backups in bytes.
+    pub max_total_size: Option<u64>,
+    /// Maximum usage of the account's storage quota in percent, counting every file
+    /// in the account, not only the backups.
+    pub max_quota_percent: Option<f64>,
+}
+
+/// Whether a backup is kept by a `RetentionPolicy`, and why.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RetentionDecision {
+    pub keep: bool,
+    /// Pinned backups are always kept, even over the size budget.
+    pub pinned: bool,
+    /// The rules keeping the backup, or why it is removed.
+    pub reasons: Vec<String>,
+}
+
+/// Parses a duration like `1y6m`, `2w3d` or `36h`.
+pub fn parse_duration(duration: &str) -> Result<Duration, InvalidRetentionError> {
+    let invalid = || InvalidRetentionError {
+        reason: format!("`{}` is not a valid duration, use e.g. `1y6m`, `2w3d` or `36h`.", duration)
+    };
+
+    let mut total = Duration::zero();
+    let mut number = String::new();
+
+    for c in duration.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let amount: i64 = number.parse().map_err(|_| invalid())?;
+        number.clear();
+        // Durations too long to be represented are reported instead of overflowing.
+        let part = match c {
+            'h' => Duration::try_hours(amount),
+            'd' => Duration::try_days(amount),
+            'w' => Duration::try_weeks(amount),
+            'm' => amount.checked_mul(30).and_then(Duration::try_days),
+            'y' => amount.checked_mul(365).and_then(Duration::try_days),
+            _ => return Err(invalid()),
+        };
+        total = part.and_then(|x| total.checked_add(&x)).ok_or_else(invalid)?;
+    }
+
+    if !number.is_empty() || total.is_zero() {
+        return Err(invalid());
+    }
+
+    Ok(total)
+}
+
+/// Returns the date of the oldest backup kept by `keep_within`, counted back from `newest`.
+///
+/// # Errors
+///
+/// * `InvalidRetentionError` if `within` isn't a valid duration, or reaches back
+///   before the earliest date that can be represented.
+fn keep_within_cutoff<Tz: TimeZone>(newest: DateTime<Tz>, within: &str) -> Result<DateTime<Tz>, InvalidRetentionError> {
+    newest.checked_sub_signed(parse_duration(within)?).ok_or_else(|| InvalidRetentionError {
+        reason: format!("`{}` reaches back too far, use a shorter duration.", within)
+    })
+}
+
+impl RetentionPolicy {
+    /// The policy used before retention became configurable: keep the 10 newest backups.
+    pub fn legacy() -> Self {
+        RetentionPolicy { keep_last: Some(10), ..Default::default() }
+    }
+
+    /// Whether any of the `keep_*` rules is set.
+    fn has_keep_rules(&self) -> bool {
+        let counts = [self.keep_last, self.keep_daily, self.keep_weekly, self.keep_monthly, self.keep_yearly];
+        self.keep_within.is_some() || counts.iter().any(|x| x.unwrap_or(0) > 0)
+    }
+
+    /// Whether the policy limits the size of the backups.
+    pub fn has_budget(&self) -> bool {
+        self.max_total_size.is_some() || self.max_quota_percent.is_some()
+    }
+
+    /// Checks that the policy keeps at least one backup and that its limits are valid.
+    pub fn validate(&self) -> Result<(), InvalidRetentionError> {
+        if let Some(within) = &self.keep_within {
+            keep_within_cutoff(Utc::now(), within)?;
+        }
+
+        if let Some(percent) = self.max_quota_percent {
+            if !(percent > 0.0 && percent <= 100.0) {
+                return Err(InvalidRetentionError {
+                    reason: format!("`max_quota_percent` must be between 0 and 100, found {}.", percent)
+                });
+            }
+        }
+
+        if !self.has_keep_rules() && !self.has_budget() {
+            return Err(InvalidRetentionError {
+                reason: String::from("The retention policy would remove every backup, set at least one `keep_*` or `max_*` rule.")
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Returns how many bytes the backups may take up in total, or `None` if the policy
+    /// doesn't limit their size.
+    ///
+    /// # Arguments
+    ///
+    /// * `backups_size` - Current total size of the backups in bytes.
+    /// * `quota` - Storage quota of the account, needed if `max_quota_percent` is set.
+    ///   Everything in the account that isn't a backup is counted against the quota too.
+    pub fn size_budget(&self, backups_size: u64, quota: Option<&StorageQuotas>) -> Option<u64> {
+        let quota_budget = match (self.max_quota_percent, quota) {
+            (Some(percent), Some(quota)) => {
+                let allowed = (quota.memory_total as f64 * percent / 100.0) as u64;
+                let other_files = quota.memory_used.saturating_sub(backups_size);
+                Some(allowed.saturating_sub(other_files))
+            }
+            (Some(_), None) => {
+                warn!("`max_quota_percent` is set, but the storage quota is unknown.");
+                None
+            }
+            (None, _) => None,
+        };
+
+        match (self.max_total_size, quota_budget) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Removes the oldest of the kept backups until the kept ones fit into `budget`.
+    /// The newest backup, pinned backups and backups without a date are never removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `backups` - Creation date and size in bytes of the backups.
+    /// * `budget` - Maximum total size of the kept backups in bytes, see `size_budget`.
+    /// * `decisions` - Decisions made by `apply`, in the same order as `backups`.
+    pub fn apply_budget(&self, backups: &[(Option<DateTime<Utc>>, u64)], budget: u64, decisions: &mut [RetentionDecision]) {
+        let mut total: u64 = backups.iter().zip(decisions.iter())
+            .filter(|(_, decision)| decision.keep)
+            .map(|((_, size), _)| size)
+            .sum();
+
+        let mut oldest_first: Vec<(usize, DateTime<Utc>)> = backups.iter()
+            .enumerate()
+            .filter_map(|(i, (timestamp, _))| timestamp.map(|x| (i, x)))
+            .collect();
+        oldest_first.sort_by_key(|x| x.1);
+        // The newest backup is the one most likely to be needed.
+        oldest_first.pop();
+
+        for (i, _) in oldest_first {
+            if total <= budget {
+                return;
+            }
+            if decisions[i].keep && !decisions[i].pinned {
+                decisions[i] = RetentionDecision {
+                    keep: false,
+                    pinned: false,
+                    reasons: vec![format!("over the size budget of {}", utils::format_size(budget))],
+                };
+                total -= backups[i].1;
+            }
+        }
+
+        if total > budget {
+            warn!("The newest backup alone is over the size budget of {}.", utils::format_size(budget));
+        }
+    }
+
+    /// Decides which of the backups to keep.
+    ///
+    /// # Arguments
+    ///
+    /// * `timestamps` - Creation dates of the backups, oldest first. Backups without a
+    ///   date are always kept, since there is no telling how old they are.
+    ///
+    /// # Returns
+    ///
+    /// Returns a decision for every backup, in the same order as `timestamps`.
+    ///
+    /// # Errors
+    ///
+    /// * `InvalidRetentionError` if the policy isn't valid, see `validate`.
+    pub fn apply(&self, timestamps: &[Option<DateTime<Utc>>]) -> Result<Vec<RetentionDecision>, InvalidRetentionError> {
+        self.validate()?;
+
+        let mut decisions: Vec<RetentionDecision> = timestamps.iter()
+            .map(|timestamp| match timestamp {
+                Some(_) => RetentionDecision { keep: false, pinned: false, reasons: Vec::new() },
+                None => RetentionDecision { keep: true, pinned: false, reasons: vec![String::from("unknown creation date")] },
+            })
+            .collect();
+
+        // Indices of the dated backups, newest first.
+        let mut newest_first: Vec<(usize, DateTime<Local>)> = timestamps.iter()
+            .enumerate()
+            .filter_map(|(i, timestamp)| timestamp.map(|x| (i, x.with_timezone(&Local))))
+            .collect();
+        // Of backups with the same timestamp, the later one in `timestamps` is the newer.
+        newest_first.sort_by_key(|x| std::cmp::Reverse((x.1, x.0)));
+
+        if !self.has_keep_rules() {
+            // Only the size budget limits which backups are kept.
+            for (i, _) in newest_first.iter() {
+                decisions[*i].reasons.push(String::from("within the size budget"));
+            }
+        }
+
+        if let Some(keep_last) = self.keep_last {
+            for (i, _) in newest_first.iter().take(keep_last) {
+                decisions[*i].reasons.push(format!("one of the last {}", keep_last));
+            }
+        }
+
+        if let (Some(within), Some((_, newest))) = (&self.keep_within, newest_first.first()) {
+            let oldest_kept = keep_within_cutoff(*newest, within)?;
+            for (i, timestamp) in newest_first.iter() {
+                if *timestamp >= oldest_kept {
+                    decisions[*i].reasons.push(format!("within {} of the newest backup", within));
+                }
+            }
+        }
+
+        let buckets: [(Option<usize>, &str, BucketFn); 4] = [
+            (self.keep_daily, "daily", |x| x.format("%Y-%m-%d").to_string()),
+            (self.keep_weekly, "weekly", |x| format!("{}-W{:02}", x.iso_week().year(), x.iso_week().week())),
+            (self.keep_monthly, "monthly", |x| x.format("%Y-%m").to_string()),
+            (self.keep_yearly, "yearly", |x| x.format("%Y").to_string()),
+        ];
+
+        for (count, rule, bucket_of) in buckets {
+            let Some(count) = count else {
+                continue;
+            };
+
+            let mut last_bucket: Option<String> = None;
+            let mut kept = 0;
+            for (i, timestamp) in newest_first.iter() {
+                if kept == count {
+                    break;
+                }
+                let bucket = bucket_of(timestamp);
+                // Backups are visited newest first, so the first one of each bucket is its newest.
+                if last_bucket.as_ref() != Some(&bucket) {
+                    decisions[*i].reasons.push(format!("{} backup of {}", rule, bucket));
+                    last_bucket = Some(bucket);
+                    kept += 1;
+                }
+            }
+        }
+
+        for decision in decisions.iter_mut() {
+            if decision.reasons.is_empty() {
+                decision.reasons.push(String::from("not kept by any rule"));
+            } else {
+                decision.keep = true;
+            }
+        }
+
+        Ok(decisions)
+    }
+}
@@ -0,0 +1,167 @@
+//! Information about the backups stored in the cloud drive.
+
+use chrono::{DateTime, Utc};
+use mega::{Node, StorageQuotas};
+use serde::Serialize;
+
+use crate::error::InvalidRetentionError;
+use crate::pins::Pins;
+use crate::retention::{RetentionDecision, RetentionPolicy};
+use crate::utils;
+
+/// Format of the output printed by commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable, aligned table.
+    Table,
+    /// A single JSON document.
+    Json,
+}
+
+/// A backup stored in the backup folder.
+#[derive(Debug, Clone, Serialize)]
+pub struct Snapshot {
+    pub name: String,
+    /// Size of the archive in bytes.
+    pub size: u64,
+    pub created_at: Option<DateTime<Utc>>,
+    /// Whether the backup would be deleted by the next pruning.
+    pub prune: bool,
+    /// Why the backup is kept or pruned.
+    pub reasons: Vec<String>,
+    /// Pinned backups are never pruned.
+    pub pinned: bool,
+    pub tag: Option<String>,
+    pub note: Option<String>,
+}
+
+impl Snapshot {
+    pub fn from_node(node: &Node) -> Self {
+        Snapshot {
+            name: String::from(node.name()),
+            size: node.size(),
+            created_at: node.created_at(),
+            prune: false,
+            reasons: Vec::new(),
+            pinned: false,
+            tag: None,
+            note: None,
+        }
+    }
+
+    /// Marks the snapshot as pinned if it is in `pins`.
+    pub fn with_pins(mut self, pins: &Pins) -> Self {
+        if let Some(pin) = pins.get(&self.name) {
+            self.pinned = true;
+            self.tag = pin.tag.clone();
+            self.note = pin.note.clone();
+        }
+        self
+    }
+}
+
+/// Marks the snapshots that would be pruned under the retention policy, noting
+/// why each of them is kept or pruned.
+///
+/// # Arguments
+///
+/// * `snapshots` - Every backup in the backup folder.
+/// * `policy` - Rules of which backups to keep.
+/// * `quota` - Storage quota of the account, used if the policy has `max_quota_percent` set.
+///
+/// # Errors
+///
+/// * `InvalidRetentionError` if the policy isn't valid.
+pub fn mark_obsolete(snapshots: &mut [Snapshot], policy: &RetentionPolicy, quota: Option<&StorageQuotas>) -> Result<(), InvalidRetentionError> {
+    let timestamps: Vec<_> = snapshots.iter().map(|x| x.created_at).collect();
+    let mut decisions = policy.apply(&timestamps)?;
+
+    for (snapshot, decision) in snapshots.iter().zip(decisions.iter_mut()) {
+        if snapshot.pinned {
+            decision.keep = true;
+            decision.pinned = true;
+            let reason = match &snapshot.tag {
+                Some(tag) => format!("pinned as {:?}", tag),
+                None => String::from("pinned"),
+            };
+            decision.reasons.retain(|x| x != "not kept by any rule");
+            decision.reasons.insert(0, reason);
+        }
+    }
+
+    let backups_size = snapshots.iter().map(|x| x.size).sum();
+    if let Some(budget) = policy.size_budget(backups_size, quota) {
+        let backups: Vec<_> = snapshots.iter().map(|x| (x.created_at, x.size)).collect();
+        policy.apply_budget(&backups, budget, &mut decisions);
+    }
+
+    for (snapshot, decision) in snapshots.iter_mut().zip(decisions) {
+        snapshot.prune = !decision.keep;
+        snapshot.reasons = decision.reasons;
+    }
+
+    Ok(())
+}
+
+/// Marks the backups to remove only so that the others fit into the size budget of
+/// the policy, ignoring its other rules. Pinned backups are kept.
+pub fn mark_over_budget(snapshots: &mut [Snapshot], policy: &RetentionPolicy, quota: Option<&StorageQuotas>) {
+    let mut decisions: Vec<_> = snapshots.iter()
+        .map(|x| RetentionDecision { keep: true, pinned: x.pinned, reasons: Vec::new() })
+        .collect();
+
+    let backups_size = snapshots.iter().map(|x| x.size).sum();
+    if let Some(budget) = policy.size_budget(backups_size, quota) {
+        let backups: Vec<_> = snapshots.iter().map(|x| (x.created_at, x.size)).collect();
+        policy.apply_budget(&backups, budget, &mut decisions);
+    }
+
+    for (snapshot, decision) in snapshots.iter_mut().zip(decisions) {
+        snapshot.prune = !decision.keep;
+        snapshot.reasons = decision.reasons;
+    }
+}
+
+/// Prints the snapshots to stdout in the given format.
+pub fn print_snapshots(snapshots: &[Snapshot], format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(snapshots)?),
+        OutputFormat::Table => {
+            let name_width = snapshots.iter()
+                .map(|snapshot| snapshot.name.len())
+                .chain(std::iter::once("NAME".len()))
+                .max()
+                .unwrap_or_default();
+
+            let tag_of = |snapshot: &Snapshot| match (&snapshot.tag, snapshot.pinned) {
+                (Some(tag), _) => tag.clone(),
+                (None, true) => String::from("(pinned)"),
+                (None, false) => String::from("-"),
+            };
+            let tag_width = snapshots.iter()
+                .map(|snapshot| tag_of(snapshot).len())
+                .chain(std::iter::once("TAG".len()))
+                .max()
+                .unwrap_or_default();
+
+            println!("{:<name_width$}  {:>10}  {:<19}  {:<tag_width$}  PRUNE  REASON", "NAME", "SIZE", "CREATED", "TAG");
+            for snapshot in snapshots {
+                let created_at = snapshot.created_at
+                    .map(|x| x.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string())
+                    .unwrap_or_else(|| String::from("-"));
+
+                println!(
+                    "{:<name_width$}  {:>10}  {:<19}  {:<tag_width$}  {:<5}  {}",
+                    snapshot.name,
+                    utils::format_size(snapshot.size),
+                    created_at,
+                    tag_of(snapshot),
+                    if snapshot.prune { "yes" } else { "no" },
+                    snapshot.reasons.join(", ")
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,68 @@
+//! What a backup run did.
+//!
+//! Every job run by `backup` ends with a summary, whether it succeeded or not, so
+//! scripts can read the outcome of a run from `--format json` instead of the log.
+
+use serde::Serialize;
+
+use crate::error::ErrorReport;
+use crate::snapshot::OutputFormat;
+use crate::utils;
+
+/// Outcome of a single job.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct JobSummary {
+    pub job: String,
+    /// Name of the uploaded backup, `None` if the job failed before uploading it.
+    pub archive: Option<String>,
+    /// Number of files in the backup.
+    pub files: usize,
+    /// Size of the backed up files in bytes.
+    pub size: u64,
+    /// Size of the archive in bytes.
+    pub archive_size: u64,
+    /// Backup replaced by this one, see `on_collision`.
+    pub replaced: Option<String>,
+    /// Old backups deleted by pruning.
+    pub pruned: Vec<String>,
+    /// Why the job failed.
+    pub error: Option<ErrorReport>,
+}
+
+impl JobSummary {
+    pub fn new(job: &str) -> Self {
+        JobSummary { job: String::from(job), ..Default::default() }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Prints the summaries of the jobs to stdout in the given format.
+pub fn print_summaries(summaries: &[JobSummary], format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(summaries)?),
+        OutputFormat::Table => {
+            for summary in summaries {
+                match &summary.error {
+                    None => println!(
+                        "OK      {}  {}  ({} files, {} ----> {}, {} pruned)",
+                        summary.job,
+                        summary.archive.as_deref().unwrap_or_default(),
+                        summary.files,
+                        utils::format_size(summary.size),
+                        utils::format_size(summary.archive_size),
+                        summary.pruned.len()
+                    ),
+                    Some(error) => match &summary.archive {
+                        Some(archive) => println!("FAILED  {}  {} was uploaded, but: {}", summary.job, archive, error.message),
+                        None => println!("FAILED  {}  {}", summary.job, error.message),
+                    },
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
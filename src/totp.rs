@@ -0,0 +1,73 @@
+//! Time-based one-time passwords (RFC 6238) for logging in to accounts with
+//! two-factor authentication, so that unattended backups don't need a phone.
+//!
+//! MEGA uses the common parameters: HMAC-SHA1, 30 second steps and 6 digits.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+use crate::error::CredentialsError;
+
+const STEP_SECONDS: u64 = 30;
+const DIGITS: u32 = 6;
+
+/// Decodes a base32 (RFC 4648) secret as shown by authenticator setups. Spaces,
+/// dashes, padding and lowercase letters are accepted.
+pub fn decode_base32(secret: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut buffer: u64 = 0;
+    let mut bits = 0;
+
+    for c in secret.chars().filter(|c| !matches!(c, ' ' | '-' | '=')) {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u64 - 'A' as u64,
+            c @ '2'..='7' => c as u64 - '2' as u64 + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    Some(bytes)
+}
+
+/// Returns the one-time password of `key` at the given Unix time.
+///
+/// # Arguments
+///
+/// * `key` - The decoded shared secret.
+/// * `unix_time` - Seconds since the Unix epoch.
+/// * `digits` - Length of the password.
+pub fn totp(key: &[u8], unix_time: u64, digits: u32) -> String {
+    let counter = unix_time / STEP_SECONDS;
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC takes keys of any length.");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // Dynamic truncation, RFC 4226 section 5.3.
+    let offset = (hash[19] & 0x0f) as usize;
+    let code = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+
+    format!("{:0width$}", code % 10u32.pow(digits), width = digits as usize)
+}
+
+/// Returns the current one-time password of a base32 encoded secret.
+///
+/// # Errors
+///
+/// * `CredentialsError` if the secret isn't valid base32.
+pub fn current_code(secret: &str) -> Result<String, CredentialsError> {
+    let key = decode_base32(secret)
+        .filter(|x| !x.is_empty())
+        .ok_or_else(|| CredentialsError { reason: String::from("The TOTP secret is not valid base32.") })?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or_default();
+
+    Ok(totp(&key, now, DIGITS))
+}
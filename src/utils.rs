@@ -1,47 +1,117 @@
-use serde::{Deserialize, Serialize};
-use base64::Engine;
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct SettingsEnv {
-    pub email: String,
-    pub password: String,
-    pub dirs_to_backup: Vec<String>,
-    pub dirs_to_ignore: Vec<String>
-}
-
-// TODO: Make this function's example doc run?!
-/// Reads a JSON file of base64 encoded credentials.
-/// 
-/// # Returns
-/// 
-/// * An `AuthEnv` struct of base64 decoded credentials and settings
-/// 
-/// # Examples
-/// ```ignore
-/// let auth_info = read_auth_info("./auth_env.json").unwrap();
-/// let AuthEnv { email, password } = auth_info;
-/// ```
-pub fn read_auth_info(file_path: &str) -> Result<SettingsEnv, Box<dyn std::error::Error>> {
-    // Read username and password from local settings file.
-    let contents = std::fs::read_to_string(file_path)?;
-
-    // Parse JSON
-    let auth_info: SettingsEnv = serde_json::from_str(&contents)?;
-
-    // Decode username and password
-    let email_bytes = base64::engine::general_purpose::STANDARD
-        .decode(auth_info.email)?;
-
-    let password_bytes = base64::engine::general_purpose::STANDARD
-        .decode(auth_info.password)?;
-
-    let email = String::from_utf8(email_bytes)?;
-    let password = String::from_utf8(password_bytes)?;
-
-    Ok(SettingsEnv {
-        email,
-        password,
-        dirs_to_backup: auth_info.dirs_to_backup,
-        dirs_to_ignore: auth_info.dirs_to_ignore
-    })
-}
\ No newline at end of file
+use sha2::Digest;
+
+/// Asks the user for a value on the terminal. Secrets aren't echoed where `stty` is available.
+pub fn prompt(message: &str, secret: bool) -> std::io::Result<String> {
+    use std::io::Write;
+
+    eprint!("{}", message);
+    std::io::stderr().flush()?;
+
+    let stty = |arg: &str| {
+        std::process::Command::new("stty").arg(arg)
+            .stdin(std::process::Stdio::inherit())
+            .status()
+            .is_ok_and(|x| x.success())
+    };
+    let hidden = secret && cfg!(unix) && stty("-echo");
+
+    let mut line = String::new();
+    let read = std::io::stdin().read_line(&mut line);
+
+    if hidden {
+        stty("echo");
+        eprintln!();
+    }
+    read?;
+
+    Ok(String::from(line.trim_end_matches(['\n', '\r'])))
+}
+
+/// Returns the name of this machine, or `unknown` if it can't be found out.
+pub fn hostname() -> String {
+    let from_env = std::env::var("COMPUTERNAME").or_else(|_| std::env::var("HOSTNAME"));
+    let from_file = || std::fs::read_to_string("/etc/hostname");
+    let from_command = || {
+        std::process::Command::new("hostname").output()
+            .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+    };
+
+    from_env.ok()
+        .or_else(|| from_file().ok())
+        .or_else(|| from_command().ok())
+        .map(|x| String::from(x.trim()))
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+/// Formats a size in bytes with binary units, e.g. `1536` ----> `1.5 KiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Writer that counts the bytes written through it.
+pub struct CountingWriter<W> {
+    inner: W,
+    count: u64
+}
+
+impl<W: std::io::Write> CountingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        CountingWriter { inner, count: 0 }
+    }
+
+    /// Number of bytes written so far.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl<W: std::io::Write> std::io::Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader that computes the SHA-256 checksum of the bytes read through it.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: sha2::Sha256
+}
+
+impl<R: std::io::Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        HashingReader { inner, hasher: sha2::Sha256::new() }
+    }
+
+    /// Checksum of the bytes read so far, as a lowercase hex string.
+    pub fn hex_digest(self) -> String {
+        self.hasher.finalize().iter().map(|x| format!("{:02x}", x)).collect()
+    }
+}
+
+impl<R: std::io::Read> std::io::Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
@@ -0,0 +1,267 @@
+//! Parallel, streaming directory walker.
+//!
+//! Directory listings are read by a small pool of worker threads, while a single
+//! coordinator thread walks the tree depth-first and sends the found files through
+//! a bounded channel. Entries of every directory are sorted by name, so the order
+//! in which files are yielded is the same on every run, no matter how the workers
+//! are scheduled. Memory usage is bounded by the channel's capacity and by the
+//! number of directory listings that are allowed to be prefetched at once.
+//!
+//! Symlinks are skipped with a warning rather than followed, so a link to a folder
+//! can neither fail the walk nor lead it into a cycle.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::{debug, warn};
+
+/// Number of walked entries that may wait in the channel until the consumer
+/// picks them up.
+const DEFAULT_QUEUE_BOUND: usize = 1024;
+
+/// A regular file found while walking a directory tree, or an ignored folder if
+/// they are reported.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WalkEntry {
+    /// Path of the file, starting with the walked root.
+    pub path: PathBuf,
+    /// Size of the file in bytes, 0 for ignored folders.
+    pub size: u64,
+    /// Whether this is a folder left out because of `ignore_folders`, see
+    /// `DirWalker::report_ignored`.
+    pub ignored: bool,
+}
+
+/// A single child of a listed directory.
+struct Child {
+    path: PathBuf,
+    is_dir: bool,
+    /// Folder of `ignore_folders`, which isn't walked.
+    ignored: bool,
+    size: u64,
+}
+
+type Listing = io::Result<Vec<Child>>;
+
+/// A request for a worker to list a directory.
+struct Job {
+    dir: PathBuf,
+    reply: Sender<Listing>,
+}
+
+/// Builder of a parallel walk over a single directory tree.
+///
+/// # Examples
+/// ```ignore
+/// let walk = DirWalker::new("C:\\NotesFolder")
+///     .ignore_folders(Some(vec![String::from(".git")]))
+///     .walk();
+///
+/// for entry in walk {
+///     println!("{:?}", entry?.path);
+/// }
+/// ```
+pub struct DirWalker {
+    root: PathBuf,
+    ignore_folders: Vec<String>,
+    report_ignored: bool,
+    threads: usize,
+    queue_bound: usize,
+}
+
+impl DirWalker {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        DirWalker {
+            root: root.into(),
+            ignore_folders: Vec::new(),
+            report_ignored: false,
+            threads: thread::available_parallelism().map(|n| n.get()).unwrap_or(4),
+            queue_bound: DEFAULT_QUEUE_BOUND,
+        }
+    }
+
+    /// Folder names that are skipped (together with their contents) during the walk.
+    pub fn ignore_folders(mut self, ignore_folders: Option<Vec<String>>) -> Self {
+        self.ignore_folders = ignore_folders.unwrap_or_default();
+        self
+    }
+
+    /// Also yield the ignored folders, marked as `ignored`, e.g. to tell what is left out.
+    pub fn report_ignored(mut self, report_ignored: bool) -> Self {
+        self.report_ignored = report_ignored;
+        self
+    }
+
+    /// Number of worker threads reading directory listings. At least one is always used.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Maximum number of found files that are buffered before the walk waits for the consumer.
+    pub fn queue_bound(mut self, queue_bound: usize) -> Self {
+        self.queue_bound = queue_bound.max(1);
+        self
+    }
+
+    /// Starts walking the tree in the background and returns an iterator over the found files.
+    ///
+    /// Errors are yielded in place of the entry that couldn't be read, so the consumer
+    /// decides whether to abort or to continue. Dropping the iterator stops the walk.
+    pub fn walk(self) -> Walk {
+        let (entry_tx, entry_rx) = mpsc::sync_channel(self.queue_bound);
+        let (job_tx, job_rx) = mpsc::channel::<Job>();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        let ignore_folders = Arc::new(self.ignore_folders);
+
+        for _ in 0..self.threads {
+            let job_rx = Arc::clone(&job_rx);
+            let ignore_folders = Arc::clone(&ignore_folders);
+            thread::spawn(move || loop {
+                // The lock is released as soon as a job is received, so the
+                // listing itself happens in parallel.
+                let job = match job_rx.lock() {
+                    Ok(rx) => rx.recv(),
+                    Err(_) => return,
+                };
+                match job {
+                    Ok(Job { dir, reply }) => {
+                        let _ = reply.send(list_dir(&dir, &ignore_folders));
+                    }
+                    Err(_) => return,
+                }
+            });
+        }
+
+        let coordinator = Coordinator {
+            jobs: job_tx,
+            ignore_folders,
+            report_ignored: self.report_ignored,
+            pending: HashMap::new(),
+            max_prefetch: self.threads * 4,
+            entries: entry_tx,
+        };
+        let root = self.root;
+        thread::spawn(move || coordinator.run(root));
+
+        Walk { entries: entry_rx }
+    }
+}
+
+/// Iterator over the files found by a [`DirWalker`].
+pub struct Walk {
+    entries: Receiver<io::Result<WalkEntry>>,
+}
+
+impl Iterator for Walk {
+    type Item = io::Result<WalkEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.recv().ok()
+    }
+}
+
+struct Coordinator {
+    jobs: Sender<Job>,
+    ignore_folders: Arc<Vec<String>>,
+    report_ignored: bool,
+    pending: HashMap<PathBuf, Receiver<Listing>>,
+    max_prefetch: usize,
+    entries: SyncSender<io::Result<WalkEntry>>,
+}
+
+impl Coordinator {
+    /// Walks the tree depth-first, returning early if the consumer has hung up.
+    fn run(mut self, root: PathBuf) {
+        let mut stack = match list_dir(&root, &self.ignore_folders) {
+            Ok(children) => {
+                self.prefetch(&children);
+                vec![children.into_iter()]
+            }
+            Err(e) => {
+                let _ = self.entries.send(Err(e));
+                return;
+            }
+        };
+
+        while let Some(children) = stack.last_mut() {
+            let Some(child) = children.next() else {
+                stack.pop();
+                continue;
+            };
+
+            if child.ignored {
+                let entry = WalkEntry { path: child.path, size: 0, ignored: true };
+                if self.report_ignored && self.entries.send(Ok(entry)).is_err() {
+                    return;
+                }
+            } else if child.is_dir {
+                match self.listing(&child.path) {
+                    Ok(grandchildren) => {
+                        self.prefetch(&grandchildren);
+                        stack.push(grandchildren.into_iter());
+                    }
+                    Err(e) => {
+                        if self.entries.send(Err(e)).is_err() {
+                            return;
+                        }
+                    }
+                }
+            } else {
+                let entry = WalkEntry { path: child.path, size: child.size, ignored: false };
+                if self.entries.send(Ok(entry)).is_err() {
+                    debug!("Walk consumer hung up, stopping walk of {:?}.", root);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Hands the listing of subdirectories to the workers, as long as there is room for them.
+    fn prefetch(&mut self, children: &[Child]) {
+        for child in children.iter().filter(|c| c.is_dir && !c.ignored) {
+            if self.pending.len() >= self.max_prefetch {
+                break;
+            }
+            let (reply, rx) = mpsc::channel();
+            if self.jobs.send(Job { dir: child.path.clone(), reply }).is_ok() {
+                self.pending.insert(child.path.clone(), rx);
+            }
+        }
+    }
+
+    /// Returns the listing of `dir`, either from a worker or by reading it in place.
+    fn listing(&mut self, dir: &Path) -> Listing {
+        match self.pending.remove(dir) {
+            Some(rx) => rx.recv().unwrap_or_else(|_| list_dir(dir, &self.ignore_folders)),
+            None => list_dir(dir, &self.ignore_folders),
+        }
+    }
+}
+
+/// Reads the children of `dir` sorted by name, marking the ignored folders and
+/// leaving out symlinks.
+fn list_dir(dir: &Path, ignore_folders: &[String]) -> Listing {
+    let mut children = Vec::new();
+
+    for node in dir.read_dir()? {
+        let node = node?;
+        // Doesn't follow symlinks, so they can be told apart.
+        let metadata = node.metadata()?;
+        if metadata.file_type().is_symlink() {
+            warn!("Skipping symlink: {:?}", node.path());
+        } else if metadata.is_dir() {
+            let ignored = ignore_folders.contains(&node.file_name().to_string_lossy().to_string());
+            children.push(Child { path: node.path(), is_dir: true, ignored, size: 0 });
+        } else {
+            children.push(Child { path: node.path(), is_dir: false, ignored: false, size: metadata.len() });
+        }
+    }
+
+    children.sort_by(|a, b| a.path.file_name().cmp(&b.path.file_name()));
+    Ok(children)
+}